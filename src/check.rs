@@ -0,0 +1,553 @@
+use crate::config::{Mixin, Publish, UserConfig, UserConfigError, normalized_path, yaml_message};
+use crate::convert::{ConversionError, PackageManager};
+use crate::docker::Dockerfile;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    InvalidConfig,
+    MissingMixin,
+    NoBase,
+    UnknownBase,
+    MultipleBases,
+    MissingVolume,
+    MissingEnvFile,
+    PortConflict,
+    ScriptSyntax,
+    Conversion,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Position of a value inside a source file
+#[derive(Debug, Clone, Copy, Default)]
+struct Position {
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+/// Finds the first line containing `needle`, 1 based
+fn locate(source: &str, needle: &str) -> Position {
+    source
+        .lines()
+        .enumerate()
+        .find_map(|(i, line)| {
+            line.find(needle).map(|column| Position {
+                line: Some(i + 1),
+                column: Some(column + 1),
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Extracts the line number out of a `sh -n` diagnostic.
+/// bash reports `bash: line 3: ...`, dash reports `sh: 3: ...`
+fn script_error_line(message: &str) -> Option<usize> {
+    if let Some((_, rest)) = message.split_once("line ") {
+        let digits = rest
+            .chars()
+            .take_while(|x| x.is_ascii_digit())
+            .collect::<String>();
+        if let Ok(line) = digits.parse() {
+            return Some(line);
+        }
+    }
+    message
+        .split(':')
+        .find_map(|part| part.trim().parse::<usize>().ok())
+}
+
+/// Picks the shell used for syntax checking from the shebang, falls back to `default`.
+/// Returns `None` for scripts run by something that is not a shell.
+fn script_shell<'a>(script: &'a str, default: &'a str) -> Option<&'a str> {
+    let shell = match script.lines().next().and_then(|x| x.strip_prefix("#!")) {
+        Some(shebang) => {
+            // The interpreter is the first word, `env` looks up its first non-option argument
+            let mut words = shebang.split_whitespace();
+            match words.next()? {
+                env if env.rsplit('/').next() == Some("env") => {
+                    words.find(|x| !x.starts_with('-'))?
+                }
+                interpreter => interpreter,
+            }
+        }
+        None => default.split_whitespace().next()?,
+    };
+    match shell.rsplit('/').next() {
+        Some(shell @ ("sh" | "bash" | "dash" | "zsh" | "ksh")) => Some(shell),
+        _ => None,
+    }
+}
+
+struct HostPort<'a> {
+    publish: &'a Publish,
+    path: &'a Path,
+    position: Position,
+}
+
+impl HostPort<'_> {
+    fn conflicts(&self, other: &HostPort) -> bool {
+        self.publish.conflicts(other.publish)
+    }
+}
+
+/// Loads a toolchain and collects every problem instead of stopping at the first one
+#[derive(Default)]
+pub struct Checker {
+    problems: Vec<Problem>,
+    /// Loaded mixins in the same order [`Mixin::load`] flattens them, root last
+    mixins: Vec<Mixin>,
+    sources: Vec<(PathBuf, String)>,
+}
+
+impl Checker {
+    pub fn check(path: &Path) -> Vec<Problem> {
+        let mut checker = Checker::default();
+        let Some(root) = checker.load(path, None) else {
+            return checker.problems;
+        };
+        checker.load_children(&root);
+        checker.mixins.push(root);
+
+        let user_configs = checker.load_user_configs();
+
+        checker.check_bases();
+        checker.check_volumes(&user_configs);
+        checker.check_env_files(&user_configs);
+        checker.check_ports(&user_configs);
+        checker.check_scripts();
+
+        if checker.problems.is_empty() {
+            checker.check_conversion();
+        }
+        checker.problems
+    }
+
+    fn source(&self, path: &Path) -> &str {
+        self.sources
+            .iter()
+            .find(|(x, _)| x == path)
+            .map(|(_, source)| source.as_str())
+            .unwrap_or_default()
+    }
+
+    fn push(&mut self, kind: ProblemKind, path: &Path, position: Position, message: String) {
+        self.problems.push(Problem {
+            kind,
+            path: path.to_path_buf(),
+            line: position.line,
+            column: position.column,
+            message,
+        })
+    }
+
+    /// Loads a single file, `include` is the including file and the position of the include
+    fn load(&mut self, path: &Path, include: Option<(&Path, Position)>) -> Option<Mixin> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                let (kind, at, position) = match include {
+                    Some((parent, position)) => (ProblemKind::MissingMixin, parent, position),
+                    None => (ProblemKind::InvalidConfig, path, Position::default()),
+                };
+                self.push(
+                    kind,
+                    at,
+                    position,
+                    format!("Failed to read {}: {}", path.display(), e),
+                );
+                return None;
+            }
+        };
+        let mixin = Mixin::try_from((path, BufReader::new(source.as_bytes())));
+        self.sources.push((path.to_path_buf(), source));
+        match mixin {
+            Ok(mixin) => Some(mixin),
+            Err(e) => {
+                let position = match e.location() {
+                    (_, Some((line, column))) => Position {
+                        line: Some(line),
+                        column: Some(column),
+                    },
+                    _ => Position::default(),
+                };
+                self.push(ProblemKind::InvalidConfig, path, position, e.message());
+                None
+            }
+        }
+    }
+
+    /// Loads the user configs, the ones failing to load are reported
+    fn load_user_configs(&mut self) -> Vec<(PathBuf, UserConfig)> {
+        let mut result = Vec::new();
+        for path in UserConfig::lookup_paths().unwrap_or_default() {
+            match UserConfig::load_file(&path) {
+                Ok(Some(config)) => {
+                    if let Ok(source) = fs::read_to_string(&path) {
+                        self.sources.push((path.clone(), source));
+                    }
+                    result.push((path, config));
+                }
+                Ok(None) => {}
+                Err(UserConfigError::InvalidYaml { source, .. }) => {
+                    let position = source
+                        .location()
+                        .map_or_else(Position::default, |x| Position {
+                            line: Some(x.line()),
+                            column: Some(x.column()),
+                        });
+                    let message = format!("invalid user config yaml: {}", yaml_message(&source));
+                    self.push(ProblemKind::InvalidConfig, &path, position, message);
+                }
+                Err(e @ UserConfigError::Io { .. }) => {
+                    self.push(
+                        ProblemKind::InvalidConfig,
+                        &path,
+                        Position::default(),
+                        e.to_string(),
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    fn load_children(&mut self, parent: &Mixin) {
+        for include in parent.yaml.mixin.iter().flatten() {
+            let path = normalized_path(parent, include);
+            if self.mixins.iter().any(|x| x.path == path) {
+                continue;
+            }
+            let position = locate(self.source(&parent.path), &include.display().to_string());
+            if let Some(mixin) = self.load(&path, Some((&parent.path, position))) {
+                self.load_children(&mixin);
+                self.mixins.push(mixin);
+            }
+        }
+    }
+
+    fn check_bases(&mut self) {
+        let mut problems = Vec::new();
+        let mut first: Option<&Mixin> = None;
+        for mixin in &self.mixins {
+            let Some(base) = &mixin.yaml.base else {
+                continue;
+            };
+            let position = locate(self.source(&mixin.path), "base:");
+            if let Err(e) = PackageManager::from_str(base) {
+                problems.push((ProblemKind::UnknownBase, &mixin.path, position, e));
+            }
+            match first {
+                Some(first) => problems.push((
+                    ProblemKind::MultipleBases,
+                    &mixin.path,
+                    position,
+                    ConversionError::MultipleBases {
+                        a: first.path.clone(),
+                        b: mixin.path.clone(),
+                    },
+                )),
+                None => first = Some(mixin),
+            }
+        }
+        if first.is_none()
+            && let Some(root) = self.mixins.last()
+        {
+            problems.push((
+                ProblemKind::NoBase,
+                &root.path,
+                Position::default(),
+                ConversionError::NoBase,
+            ))
+        }
+        let problems = problems
+            .into_iter()
+            .map(|(kind, path, position, e)| (kind, path.clone(), position, e.to_string()))
+            .collect::<Vec<_>>();
+        for (kind, path, position, message) in problems {
+            self.push(kind, &path, position, message);
+        }
+    }
+
+    fn check_volumes(&mut self, user_configs: &[(PathBuf, UserConfig)]) {
+        let mixin_volumes = self.mixins.iter().flat_map(|mixin| {
+            mixin.yaml.volume.iter().flatten().map(|volume| {
+                (
+                    mixin.path.clone(),
+                    volume.host_path.clone(),
+                    mixin.add_parent_path(&volume.host_path),
+                )
+            })
+        });
+        let user_volumes = user_configs.iter().flat_map(|(path, config)| {
+            config.volume.iter().flatten().map(|volume| {
+                (
+                    path.clone(),
+                    volume.host_path.clone(),
+                    volume.host_path.clone(),
+                )
+            })
+        });
+        let missing = mixin_volumes
+            .chain(user_volumes)
+            .filter(|(_, _, host_path)| !host_path.exists())
+            .collect::<Vec<_>>();
+        for (path, raw, host_path) in missing {
+            let position = locate(self.source(&path), &raw.display().to_string());
+            self.push(
+                ProblemKind::MissingVolume,
+                &path,
+                position,
+                format!("Volume host path does not exist: {}", host_path.display()),
+            );
+        }
+    }
+
+    fn check_env_files(&mut self, user_configs: &[(PathBuf, UserConfig)]) {
+        let mixin_files = self.mixins.iter().flat_map(|mixin| {
+            mixin
+                .yaml
+                .env_file
+                .iter()
+                .flatten()
+                .zip(mixin.env_files())
+                .map(|(raw, env_file)| (mixin.path.clone(), raw.clone(), env_file))
+        });
+        let user_files = user_configs.iter().flat_map(|(path, config)| {
+            config
+                .env_file
+                .iter()
+                .flatten()
+                .map(|env_file| (path.clone(), env_file.clone(), env_file.clone()))
+        });
+        let missing = mixin_files
+            .chain(user_files)
+            .filter(|(_, _, env_file)| !env_file.is_file())
+            .collect::<Vec<_>>();
+        for (path, raw, env_file) in missing {
+            let position = locate(self.source(&path), &raw.display().to_string());
+            self.push(
+                ProblemKind::MissingEnvFile,
+                &path,
+                position,
+                format!("Env file does not exist: {}", env_file.display()),
+            );
+        }
+    }
+
+    fn check_ports(&mut self, user_configs: &[(PathBuf, UserConfig)]) {
+        let mixin_ports = self.mixins.iter().flat_map(|mixin| {
+            mixin
+                .yaml
+                .publish
+                .iter()
+                .flatten()
+                .map(|publish| (mixin.path.as_path(), publish))
+        });
+        let user_ports = user_configs.iter().flat_map(|(path, config)| {
+            config
+                .publish
+                .iter()
+                .flatten()
+                .map(|publish| (path.as_path(), publish))
+        });
+        let mut seen: Vec<HostPort> = Vec::new();
+        let mut problems = Vec::new();
+        for (path, publish) in mixin_ports.chain(user_ports) {
+            let port = HostPort {
+                publish,
+                path,
+                position: locate(self.source(path), &publish.to_string()),
+            };
+            if let Some(other) = seen.iter().find(|other| other.conflicts(&port)) {
+                problems.push((
+                    port.path.to_path_buf(),
+                    port.position,
+                    format!(
+                        "Host port {} is already published by {}{}",
+                        publish.host_port,
+                        other.path.display(),
+                        other
+                            .position
+                            .line
+                            .map(|line| format!(":{}", line))
+                            .unwrap_or_default()
+                    ),
+                ));
+            }
+            seen.push(port);
+        }
+        for (path, position, message) in problems {
+            self.push(ProblemKind::PortConflict, &path, position, message);
+        }
+    }
+
+    fn check_scripts(&mut self) {
+        let mut problems = Vec::new();
+        let mut checked = HashSet::new();
+        for mixin in &self.mixins {
+            if !checked.insert(&mixin.path) {
+                continue;
+            }
+            let default_shell = mixin.yaml.script_shell.as_deref().unwrap_or("sh");
+            for script in mixin.scripts() {
+                let Some(shell) = script_shell(script.content, default_shell) else {
+                    continue;
+                };
+                let Ok(mut child) = process::Command::new(shell)
+                    .arg("-n")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                else {
+                    continue;
+                };
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(script.content.as_bytes());
+                }
+                let Ok(output) = child.wait_with_output() else {
+                    continue;
+                };
+                if output.status.success() {
+                    continue;
+                }
+                // Block scalars in the frontmatter start in the line after their key
+                let offset = match script.key {
+                    Some(key) => locate(self.source(&mixin.path), &format!("{}:", key))
+                        .line
+                        .unwrap_or_default(),
+                    None => mixin.script_offset,
+                };
+                for message in String::from_utf8_lossy(&output.stderr).lines() {
+                    let line = script_error_line(message).map(|line| line + offset);
+                    problems.push((
+                        mixin.path.clone(),
+                        Position { line, column: None },
+                        message.trim().to_string(),
+                    ));
+                }
+            }
+        }
+        for (path, position, message) in problems {
+            self.push(ProblemKind::ScriptSyntax, &path, position, message);
+        }
+    }
+
+    /// Runs the actual conversion to catch anything the checks above did not cover
+    fn check_conversion(&mut self) {
+        let Some(mut root) = self.mixins.pop() else {
+            return;
+        };
+        root.children = std::mem::take(&mut self.mixins);
+        if let Err(e) = Dockerfile::try_from(&root) {
+            self.push(
+                ProblemKind::Conversion,
+                &root.path,
+                Position::default(),
+                e.to_string(),
+            );
+        }
+    }
+}
+
+pub fn print(problems: &[Problem], format: Format) {
+    match format {
+        Format::Human => {
+            for problem in problems {
+                println!("{}", problem);
+            }
+            match problems.len() {
+                0 => println!("No problems found"),
+                1 => println!("1 problem found"),
+                n => println!("{} problems found", n),
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(problems).unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let source = "---\nbase: ubuntu:22.04\npublish:\n  - 8080:80\n---\n";
+        let position = locate(source, "8080:80");
+        assert_eq!(position.line, Some(4));
+        assert_eq!(position.column, Some(5));
+        assert_eq!(locate(source, "missing").line, None);
+    }
+
+    #[test]
+    fn test_script_error_line() {
+        assert_eq!(
+            script_error_line("sh: 3: Syntax error: end of file unexpected"),
+            Some(3)
+        );
+        assert_eq!(
+            script_error_line("bash: line 12: syntax error: unexpected end of file"),
+            Some(12)
+        );
+        assert_eq!(script_error_line("something went wrong"), None);
+    }
+
+    #[test]
+    fn test_script_shell() {
+        assert_eq!(script_shell("#!/bin/bash\necho hi", "sh"), Some("bash"));
+        assert_eq!(
+            script_shell("#!/usr/bin/env zsh\necho hi", "sh"),
+            Some("zsh")
+        );
+        assert_eq!(script_shell("#!/bin/bash -e\necho hi", "sh"), Some("bash"));
+        assert_eq!(
+            script_shell("#!/usr/bin/env bash -x\necho hi", "sh"),
+            Some("bash")
+        );
+        assert_eq!(
+            script_shell("#!/usr/bin/env -S zsh -e\necho hi", "sh"),
+            Some("zsh")
+        );
+        assert_eq!(script_shell("#!/usr/bin/python3 -u\nprint()", "sh"), None);
+        assert_eq!(script_shell("#!/usr/bin/python3\nprint()", "sh"), None);
+        assert_eq!(script_shell("echo hi", "sh"), Some("sh"));
+        assert_eq!(script_shell("echo hi", "/bin/bash -e"), Some("bash"));
+    }
+}
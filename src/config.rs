@@ -1,6 +1,7 @@
 mod mixin;
 
 use crate::docker::Dockerfile;
+use crate::forward::{Forward, Gui};
 use derive_more::{Display, Error, From};
 pub use mixin::*;
 use serde::{Deserialize, Serialize};
@@ -24,17 +25,45 @@ fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct UserConfig {
-    publish: Option<Vec<Publish>>,
-    volume: Option<Vec<Volume>>,
-    env: Option<HashMap<String, String>>,
+    pub publish: Option<Vec<Publish>>,
+    pub volume: Option<Vec<Volume>>,
+    /// `~` or `null` unsets a variable defined by the toolchain or an earlier config
+    pub env: Option<HashMap<String, Option<String>>>,
+    /// Dotenv files, relative to the config
+    pub env_file: Option<Vec<PathBuf>>,
+    /// Host variables passed into the container, `*` may be used as wildcard
+    pub passthrough: Option<Vec<String>>,
+    /// Forward the host's ssh agent, enabled by default
+    pub ssh_agent: Option<bool>,
+    /// Mount the host's ~/.ssh/known_hosts read-only
+    pub ssh_known_hosts: Option<bool>,
+    /// Opt-in host resources forwarded into the container
+    pub forward: Option<Vec<Forward>>,
+    /// Toggles GUI forwards, only X11 is enabled by default
+    pub gui: Option<Gui>,
+    /// Overrides `docker_socket:` of toolchains
+    pub docker_socket: Option<bool>,
+}
+
+/// User config that exists but cannot be loaded
+#[derive(Debug, Display, Error)]
+pub enum UserConfigError {
+    #[display("Failed to read user config {}: {}", path.display(), source)]
+    Io { path: PathBuf, source: io::Error },
+    #[display("Invalid user config {}: {}", path.display(), source)]
+    InvalidYaml {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
 }
 
 impl UserConfig {
-    pub fn load() -> io::Result<Self> {
+    /// Paths user configs are loaded from, in merge order
+    pub fn lookup_paths() -> io::Result<Vec<PathBuf>> {
         let home = env::home_dir();
         let current = env::current_dir()?;
 
-        let configs: Vec<UserConfig> = home
+        Ok(home
             .map(|path| {
                 [
                     path.join(PathBuf::from(".mc2config.yaml")),
@@ -47,64 +76,85 @@ impl UserConfig {
                 current.join(PathBuf::from_iter([".mc2", ".mc2config.yaml"])),
             ]])
             .flatten()
-            .filter_map(|path| {
-                if path.exists() && path.is_file() {
-                    let file = BufReader::new(File::open(&path).ok()?);
-                    let mut config: UserConfig = serde_yaml::from_reader(file).ok()?;
-                    match (&path.parent(), &mut config.volume) {
-                        (Some(parent), Some(volume)) => volume.iter_mut().for_each(|volume| {
-                            volume.host_path = normalize_path(&volume.host_path);
-                            if volume.host_path.is_relative() {
-                                volume.host_path = parent.join(&volume.host_path)
-                            }
-                        }),
-                        _ => {}
-                    }
-                    Some(Ok(config))
+            .collect())
+    }
+
+    /// Loads a single config file, `None` if it does not exist. Relative volume and env file
+    /// paths are resolved against its directory.
+    pub fn load_file(path: &Path) -> Result<Option<Self>, UserConfigError> {
+        if !path.exists() || !path.is_file() {
+            return Ok(None);
+        }
+        let file = File::open(path).map_err(|source| UserConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut config: UserConfig =
+            serde_yaml::from_reader(BufReader::new(file)).map_err(|source| {
+                UserConfigError::InvalidYaml {
+                    path: path.to_path_buf(),
+                    source,
+                }
+            })?;
+        if let Some(parent) = path.parent() {
+            let resolve = |path: &PathBuf| {
+                let path = normalize_path(path);
+                if path.is_relative() {
+                    parent.join(&path)
                 } else {
-                    None
+                    path
                 }
-            })
-            .collect::<io::Result<Vec<_>>>()?;
-        let publish = Vec::from_iter(
-            configs
-                .iter()
-                .filter_map(|config| config.publish.as_ref())
-                .cloned()
-                .flatten(),
-        );
-        let volume = Vec::from_iter(
-            configs
-                .iter()
-                .filter_map(|config| config.volume.as_ref())
-                .cloned()
-                .flatten(),
-        );
-        let env = HashMap::from_iter(
-            configs
-                .iter()
-                .filter_map(|config| config.env.as_ref())
-                .cloned()
-                .flatten(),
-        );
-        Ok(Self {
-            publish: Some(publish),
-            volume: Some(volume),
-            env: Some(env),
-        })
+            };
+            for volume in config.volume.iter_mut().flatten() {
+                volume.host_path = resolve(&volume.host_path);
+            }
+            for env_file in config.env_file.iter_mut().flatten() {
+                *env_file = resolve(env_file);
+            }
+        }
+        Ok(Some(config))
+    }
+
+    /// Loads every existing user config together with its path, fails on the first one
+    /// that cannot be loaded
+    pub fn load_all() -> io::Result<Vec<(PathBuf, Self)>> {
+        let mut result = Vec::new();
+        for path in Self::lookup_paths()? {
+            let config = Self::load_file(&path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            result.extend(config.map(|config| (path, config)));
+        }
+        Ok(result)
     }
-    pub fn append_docker(&self, dockerfile: &mut Dockerfile) {
+
+    pub fn append_docker(&self, dockerfile: &mut Dockerfile) -> io::Result<()> {
         if let Some(publish) = &self.publish {
             dockerfile.add_publishes(publish.iter());
         }
         if let Some(volume) = &self.volume {
             dockerfile.add_volumes(volume.iter());
         }
-        if let Some(env) = &self.env {
-            for (k, v) in env {
-                dockerfile.add_env(k, v);
-            }
-        };
+        if let Some(enable) = self.ssh_agent {
+            dockerfile.set_ssh_agent(enable);
+        }
+        if let Some(enable) = self.ssh_known_hosts {
+            dockerfile.set_ssh_known_hosts(enable);
+        }
+        if let Some(forward) = &self.forward {
+            dockerfile.add_forwards(forward.iter());
+        }
+        if let Some(gui) = &self.gui {
+            dockerfile.merge_gui(gui);
+        }
+        let env = resolve_env(
+            self.env_file.iter().flatten(),
+            self.passthrough.iter().flatten(),
+            self.env.as_ref(),
+        )?;
+        for entry in env {
+            dockerfile.set_env(&entry.key, entry.value.as_deref());
+        }
+        Ok(())
     }
 }
 
@@ -122,6 +172,17 @@ pub struct Publish {
     pub machine_port: u16,
 }
 
+impl Publish {
+    /// Whether both bind the same host port, a port without IP binds every address
+    pub fn conflicts(&self, other: &Publish) -> bool {
+        self.host_port == other.host_port
+            && match (&self.host_ip, &other.host_ip) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
 impl Display for Publish {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if let Some(host_ip) = &self.host_ip {
@@ -214,6 +275,129 @@ impl FromStr for Volume {
     }
 }
 
+/// Environment variable of a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvEntry {
+    pub key: String,
+    /// `None` unsets the variable
+    pub value: Option<String>,
+    /// Env file the variable was read from
+    pub file: Option<PathBuf>,
+    /// Passed through from the host
+    pub host: bool,
+}
+
+/// Parses a dotenv file. Bare keys inherit the host value and are skipped if it is not set.
+pub fn parse_env_file(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.strip_prefix("export ").unwrap_or(line).trim_start())
+        .filter_map(|line| match line.split_once('=') {
+            Some((k, v)) => Some((k.trim().to_string(), unquote(v.trim()))),
+            None => env::var(line).ok().map(|v| (line.to_string(), v)),
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        // One pass, so an escaped backslash is not read as the start of another escape
+        let mut result = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(x) = chars.next() {
+            match x {
+                '\\' => match chars.next() {
+                    Some('n') => result.push('\n'),
+                    Some(escaped @ ('"' | '\\')) => result.push(escaped),
+                    Some(other) => result.extend(['\\', other]),
+                    None => result.push('\\'),
+                },
+                _ => result.push(x),
+            }
+        }
+        return result;
+    }
+    // Unquoted values may carry a trailing comment
+    match value.find(" #") {
+        Some(index) => value[..index].trim_end().to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// Host variables matching a passthrough pattern, sorted by name
+pub fn host_env(pattern: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::from_iter(env::vars().filter(|(k, _)| glob_match(pattern, k)));
+    vars.sort();
+    vars
+}
+
+/// Environment of a config in order of precedence: env files, host passthrough, `env:`
+pub fn resolve_env<'a>(
+    env_files: impl IntoIterator<Item = &'a PathBuf>,
+    passthrough: impl IntoIterator<Item = &'a String>,
+    env: Option<&HashMap<String, Option<String>>>,
+) -> io::Result<Vec<EnvEntry>> {
+    let mut result = Vec::new();
+    for path in env_files {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read env file {}: {}", path.display(), e),
+            )
+        })?;
+        result.extend(
+            parse_env_file(&content)
+                .into_iter()
+                .map(|(key, value)| EnvEntry {
+                    key,
+                    value: Some(value),
+                    file: Some(path.clone()),
+                    host: false,
+                }),
+        );
+    }
+    for pattern in passthrough {
+        result.extend(host_env(pattern).into_iter().map(|(key, value)| EnvEntry {
+            key,
+            value: Some(value),
+            file: None,
+            host: true,
+        }));
+    }
+    let mut env = Vec::from_iter(env.into_iter().flatten());
+    env.sort();
+    result.extend(env.into_iter().map(|(key, value)| EnvEntry {
+        key: key.clone(),
+        value: value.clone(),
+        file: None,
+        host: false,
+    }));
+    Ok(result)
+}
+
+/// Matches `text` against a pattern where `*` matches any sequence of characters
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
 pub fn get_alias_from_config(machine: &str) -> Option<PathBuf> {
     {
         [
@@ -291,10 +475,46 @@ mod tests {
         assert_eq!(&expected.to_string(), raw);
     }
 
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("AWS_*", "AWS_REGION"));
+        assert!(glob_match("*.log", "build.log"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("AWS_*", "CARGO_HOME"));
+        assert!(!glob_match("*.log", "build.txt"));
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let content = "# comment\n\nA=1\nexport B = two words\nC=\"quoted \\\"x\\\"\"\nF=\"C:\\\\new\\nline\"\nD='single # not a comment'\nE=value # comment\nMC2_SURELY_NOT_SET\n";
+        assert_eq!(
+            parse_env_file(content),
+            Vec::from([
+                ("A".into(), "1".into()),
+                ("B".into(), "two words".into()),
+                ("C".into(), "quoted \"x\"".into()),
+                ("F".into(), "C:\\new\nline".into()),
+                ("D".into(), "single # not a comment".into()),
+                ("E".into(), "value".into()),
+            ])
+        );
+    }
+
     #[test]
     fn test_user_config() {
         let expected = UserConfig {
-            env: Some(HashMap::from([("A".into(), "B".into())])),
+            env: Some(HashMap::from([("A".into(), Some("B".into()))])),
+            env_file: None,
+            passthrough: None,
+            ssh_agent: None,
+            ssh_known_hosts: None,
+            forward: Some(Vec::from([Forward::Git])),
+            gui: Some(Gui {
+                audio: Some(false),
+                ..Gui::default()
+            }),
+            docker_socket: None,
             publish: Some(Vec::from(["8080:80".parse().unwrap()])),
             volume: Some(Vec::from(["/usr/bin/test:/bin".parse().unwrap()])),
         };
@@ -1,10 +1,14 @@
-use crate::config::{Publish, Volume};
+use crate::config::{EnvEntry, Publish, Volume, normalize_path, resolve_env};
+use derive_more::{Display, Error};
 use serde::Deserialize;
+use serde_with::DeserializeFromStr;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub struct Mixin {
@@ -12,6 +16,8 @@ pub struct Mixin {
     pub yaml: MixinYaml,
     pub children: Vec<Mixin>,
     pub script: Option<String>,
+    /// Number of lines in front of the script section
+    pub script_offset: usize,
 }
 
 impl Mixin {
@@ -20,7 +26,7 @@ impl Mixin {
         [
             PathBuf::from(&machine_file_name),
             PathBuf::from_iter([".mc", &machine_file_name]),
-            PathBuf::from_iter([".mc", &name, &machine_file_name]),
+            PathBuf::from_iter([".mc", name, &machine_file_name]),
         ]
         .to_vec()
     }
@@ -33,11 +39,9 @@ impl Mixin {
         .to_vec()
     }
 
-    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Mixin> {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Mixin, MixinError> {
         let path: &Path = path.as_ref();
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut mixin = Mixin::try_from((path, reader))?;
+        let mut mixin = open(path)?;
 
         let mut children = Vec::new();
         load_mixins(&mixin, &mut children)?;
@@ -46,6 +50,50 @@ impl Mixin {
         Ok(mixin)
     }
 
+    /// Env files with `~` and relative paths resolved
+    pub fn env_files(&self) -> Vec<PathBuf> {
+        self.yaml
+            .env_file
+            .iter()
+            .flatten()
+            .map(|path| self.add_parent_path(&normalize_path(path)))
+            .collect()
+    }
+
+    /// Scripts in execution order: `root_script`, the script section, `user_script`
+    pub fn scripts(&self) -> Vec<Script<'_>> {
+        let script_user = self.yaml.script_user.unwrap_or_default();
+        [
+            (
+                Some("root_script"),
+                ScriptUser::Root,
+                &self.yaml.root_script,
+            ),
+            (None, script_user, &self.script),
+            (
+                Some("user_script"),
+                ScriptUser::User,
+                &self.yaml.user_script,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, user, content)| {
+            content
+                .as_deref()
+                .map(|content| Script { key, user, content })
+        })
+        .collect()
+    }
+
+    /// Environment of this mixin, see [`resolve_env`]
+    pub fn resolve_env(&self) -> io::Result<Vec<EnvEntry>> {
+        resolve_env(
+            &self.env_files(),
+            self.yaml.passthrough.iter().flatten(),
+            self.yaml.env.as_ref(),
+        )
+    }
+
     pub fn add_parent_path<T: AsRef<Path>>(&self, path: &T) -> PathBuf {
         let path: &Path = path.as_ref();
         match self.path.parent() {
@@ -55,45 +103,312 @@ impl Mixin {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct MixinYaml {
     pub base: Option<String>,
     pub install: Option<Vec<String>>,
     pub mixin: Option<Vec<PathBuf>>,
     pub publish: Option<Vec<Publish>>,
     pub volume: Option<Vec<Volume>>,
+    pub env: Option<HashMap<String, Option<String>>>,
+    /// Dotenv files, relative to the mixin
+    pub env_file: Option<Vec<PathBuf>>,
+    /// Host variables passed into the container, `*` may be used as wildcard
+    pub passthrough: Option<Vec<String>>,
+    /// Gives the container access to the host's docker daemon
+    pub docker_socket: Option<bool>,
+    /// Working directory of the scripts and the image
+    pub workdir: Option<String>,
+    /// Wraps the command run in the container
+    pub entrypoint: Option<CommandLine>,
+    /// Shell for the scripts and RUN instructions, exec form e.g. `[/bin/bash, -c]`
+    pub shell: Option<Vec<String>>,
+    pub labels: Option<HashMap<String, String>>,
+    /// Command reporting whether the container is healthy
+    pub healthcheck: Option<CommandLine>,
+    /// Who runs the script section, the user by default
+    pub script_user: Option<ScriptUser>,
+    /// Interpreter for scripts without shebang, `/bin/sh` by default
+    pub script_shell: Option<String>,
+    /// Script run as root before the script section
+    pub root_script: Option<String>,
+    /// Script run as the user after the script section
+    pub user_script: Option<String>,
+    /// Scripts run each time a container is started or entered
+    pub hooks: Option<Hooks>,
+    /// Separate image building artifacts, it is not part of the final image
+    pub stage: Option<Stage>,
+    /// Paths copied from `stage` into the image
+    pub artifacts: Option<Vec<Artifact>>,
+    /// Directories kept between builds for the scripts, e.g. `~/.cargo/registry`
+    pub cache_dirs: Option<Vec<String>>,
+    /// Containers started next to the machine, reachable by their name
+    pub services: Option<HashMap<String, Service>>,
+    /// Conditions awaited on the host before the command runs
+    pub wait_for: Option<Vec<WaitFor>>,
+}
+
+/// Readiness condition, exactly one of `tcp`, `http`, `file` and `command` is set
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WaitFor {
+    /// `<host>:<port>` accepting connections
+    pub tcp: Option<String>,
+    /// `http://` URL answering with status 200
+    pub http: Option<String>,
+    /// Path existing, relative to the mixin
+    pub file: Option<PathBuf>,
+    /// Shell command exiting successfully
+    pub command: Option<String>,
+    /// Seconds, 30 by default
+    pub timeout: Option<u64>,
+}
+
+/// Sidecar container running either a plain image or another mc2 machine
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Service {
+    pub image: Option<String>,
+    pub machine: Option<String>,
+    /// Overrides the command of the image
+    pub command: Option<CommandLine>,
     pub env: Option<HashMap<String, String>>,
+    pub publish: Option<Vec<Publish>>,
+    pub volume: Option<Vec<Volume>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stage {
+    /// Defaults to the file name of the mixin
+    pub name: Option<String>,
+    pub base: String,
+    pub install: Option<Vec<String>>,
+    /// Runs as root, honors its shebang
+    pub script: Option<String>,
+}
+
+/// `<stage path>[:<image path>]`, the image path defaults to the stage path
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeFromStr)]
+pub struct Artifact {
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+impl FromStr for Artifact {
+    type Err = ParseArtifactError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, target) = s.split_once(':').unwrap_or((s, s));
+        if source.is_empty() || target.is_empty() || target.contains(':') {
+            return Err(ParseArtifactError::InvalidFormat);
+        }
+        Ok(Self {
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+        })
+    }
+}
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum ParseArtifactError {
+    #[display("Invalid artifact format: <stage_path>[:<image_path>]")]
+    InvalidFormat,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Hooks {
+    /// Runs before the command on every container start
+    pub on_start: Option<String>,
+    /// Sourced by interactive bash shells
+    pub on_enter: Option<String>,
+    /// Runs after the command exited
+    pub on_exit: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptUser {
+    Root,
+    #[default]
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Script<'a> {
+    /// Frontmatter key the script is defined by, `None` for the script section
+    pub key: Option<&'static str>,
+    pub user: ScriptUser,
+    pub content: &'a str,
+}
+
+/// Command given either as shell string or as list of arguments
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl CommandLine {
+    /// Arguments to run the command without an additional shell
+    pub fn exec_form(&self) -> Vec<String> {
+        match self {
+            CommandLine::Shell(command) => {
+                Vec::from(["/bin/sh".into(), "-c".into(), command.clone()])
+            }
+            CommandLine::Exec(args) => args.clone(),
+        }
+    }
+
+    /// Like [`Self::exec_form`], but the shell form receives the arguments appended by
+    /// `docker run` as `"$@"`
+    pub fn entrypoint_form(&self) -> Vec<String> {
+        match self {
+            CommandLine::Shell(command) => Vec::from([
+                "/bin/sh".into(),
+                "-c".into(),
+                format!("{} \"$@\"", command),
+                "/bin/sh".into(),
+            ]),
+            CommandLine::Exec(args) => args.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MixinError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Empty {
+        path: PathBuf,
+    },
+    MissingEnd {
+        path: PathBuf,
+    },
+    InvalidYaml {
+        path: PathBuf,
+        /// Line in the file, 1 based
+        line: usize,
+        /// Column in the line, 1 based
+        column: usize,
+        message: String,
+        /// Offending line of the file
+        snippet: String,
+    },
+    /// Error inside a file included by `parent` through `mixin:`
+    Included {
+        parent: PathBuf,
+        source: Box<MixinError>,
+    },
+}
+
+impl MixinError {
+    /// File and position the error originates from, includes are resolved
+    pub fn location(&self) -> (&Path, Option<(usize, usize)>) {
+        match self {
+            MixinError::Io { path, .. }
+            | MixinError::Empty { path }
+            | MixinError::MissingEnd { path } => (path, None),
+            MixinError::InvalidYaml {
+                path, line, column, ..
+            } => (path, Some((*line, *column))),
+            MixinError::Included { source, .. } => source.location(),
+        }
+    }
+
+    /// Innermost error of an include chain
+    fn root(&self) -> &MixinError {
+        match self {
+            MixinError::Included { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    /// Error message without position and include chain
+    pub fn message(&self) -> String {
+        match self {
+            MixinError::Io { source, .. } => format!("failed to read file: {}", source),
+            MixinError::Empty { .. } => "config was empty".to_string(),
+            MixinError::MissingEnd { .. } => {
+                "config section started with --- but missing closing ---".to_string()
+            }
+            MixinError::InvalidYaml { message, .. } => format!("invalid config yaml: {}", message),
+            MixinError::Included { source, .. } => source.message(),
+        }
+    }
 }
 
-impl Default for MixinYaml {
-    fn default() -> Self {
-        Self {
-            base: None,
-            install: None,
-            mixin: None,
-            publish: None,
-            volume: None,
-            env: None,
+impl Display for MixinError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (path, location) = self.location();
+        writeln!(f, "{}", self.message())?;
+        match (location, self.root()) {
+            (Some((line, column)), MixinError::InvalidYaml { snippet, .. }) => {
+                let gutter = " ".repeat(line.to_string().len());
+                writeln!(f, "{} --> {}:{}:{}", gutter, path.display(), line, column)?;
+                writeln!(f, "{} |", gutter)?;
+                writeln!(f, "{} | {}", line, snippet)?;
+                write!(f, "{} | {}^", gutter, " ".repeat(column - 1))?;
+            }
+            _ => write!(f, " --> {}", path.display())?,
+        }
+        let mut parents = Vec::new();
+        let mut error = self;
+        while let MixinError::Included { parent, source } = error {
+            parents.push(parent);
+            error = source;
         }
+        for parent in parents.iter().rev() {
+            write!(f, "\n included from {}", parent.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Strips serde_yaml's position, for toolchains it is relative to the frontmatter
+pub(crate) fn yaml_message(error: &serde_yaml::Error) -> String {
+    let mut message = error.to_string();
+    while let Some(index) = message.find(" at line ") {
+        let rest = &message[index + " at line ".len()..];
+        let end = rest
+            .find(|x: char| !x.is_ascii_digit())
+            .and_then(|i| rest[i..].strip_prefix(" column "))
+            .map(|rest| rest.trim_start_matches(|x: char| x.is_ascii_digit()))
+            .unwrap_or(rest);
+        message = format!("{}{}", &message[..index], end);
     }
+    message
+}
+
+fn open(path: &Path) -> Result<Mixin, MixinError> {
+    let file = File::open(path).map_err(|source| MixinError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Mixin::try_from((path, BufReader::new(file)))
 }
 
 impl<T> TryFrom<(&Path, BufReader<T>)> for Mixin
 where
     T: Read,
 {
-    type Error = io::Error;
+    type Error = MixinError;
 
     /// Parses file like this
     /// ---
     /// some config
     /// ---
     /// some script
-    fn try_from(value: (&Path, BufReader<T>)) -> Result<Mixin, io::Error> {
+    fn try_from(value: (&Path, BufReader<T>)) -> Result<Mixin, MixinError> {
         let (path, mut reader) = value;
         // Read the entire input into a string
         let mut content = String::new();
-        reader.read_to_string(&mut content)?;
+        reader
+            .read_to_string(&mut content)
+            .map_err(|source| MixinError::Io {
+                path: path.to_path_buf(),
+                source,
+            })?;
 
         // Fast path: if no leading marker, the whole file is script
         let content = content.replace("\r\n", "\n");
@@ -113,18 +428,26 @@ where
 
                 // If closing marker not found, return a format error: config must end with dashes
                 if !found_end {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "config section started with --- but missing closing ---",
-                    ));
+                    return Err(MixinError::MissingEnd {
+                        path: path.to_path_buf(),
+                    });
                 }
 
                 let config: MixinYaml =
                     serde_yaml::from_str(&cfg_lines.join("\n")).map_err(|e| {
-                        io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("invalid config yaml: {e}"),
-                        )
+                        // Frontmatter starts after the leading '---'
+                        let (line, column) = e
+                            .location()
+                            .map(|x| (x.line() + 1, x.column()))
+                            .unwrap_or((2, 1));
+                        let snippet = content.lines().nth(line - 1).unwrap_or_default();
+                        MixinError::InvalidYaml {
+                            path: path.to_path_buf(),
+                            line,
+                            column: column.min(snippet.len() + 1),
+                            message: yaml_message(&e),
+                            snippet: snippet.to_string(),
+                        }
                     })?;
 
                 // Remaining lines are script
@@ -140,6 +463,7 @@ where
                     yaml: config,
                     script,
                     children: Vec::new(),
+                    script_offset: cfg_lines.len() + 2,
                 })
             }
             Some(first) => {
@@ -159,17 +483,17 @@ where
                     yaml: MixinYaml::default(),
                     script,
                     children: Vec::new(),
+                    script_offset: 0,
                 })
             }
-            None => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "config was empty",
-            )),
+            None => Err(MixinError::Empty {
+                path: path.to_path_buf(),
+            }),
         }
     }
 }
 
-fn normalized_path(mixin: &Mixin, path: &Path) -> PathBuf {
+pub fn normalized_path(mixin: &Mixin, path: &Path) -> PathBuf {
     let parent_path = mixin.path.parent();
     let file_name = &format!("{}.yaml", path.file_name().unwrap().display());
     let path = match path.parent() {
@@ -185,20 +509,22 @@ fn normalized_path(mixin: &Mixin, path: &Path) -> PathBuf {
     )
 }
 
-fn load_mixins(parent: &Mixin, children: &mut Vec<Mixin>) -> io::Result<()> {
+fn load_mixins(parent: &Mixin, children: &mut Vec<Mixin>) -> Result<(), MixinError> {
     let Some(paths) = &parent.yaml.mixin else {
         return Ok(());
     };
 
+    let included = |source| MixinError::Included {
+        parent: parent.path.clone(),
+        source: Box::new(source),
+    };
     for path in paths {
-        let path = normalized_path(parent, &path);
-        let file = File::open(&path)?;
-        let reader = BufReader::new(file);
-        let mixin = Mixin::try_from((path.as_path(), reader))?;
-        if children.iter().any(|x| &x.path == &path) {
+        let path = normalized_path(parent, path);
+        let mixin = open(&path).map_err(included)?;
+        if children.iter().any(|x| x.path == path) {
             continue;
         }
-        load_mixins(&mixin, children)?;
+        load_mixins(&mixin, children).map_err(included)?;
         children.push(mixin);
     }
 
@@ -228,6 +554,7 @@ mod tests {
             mixin.script.as_deref(),
             Some("echo hello\n".trim_end_matches('\n'))
         );
+        assert_eq!(mixin.script_offset, 8);
     }
 
     #[test]
@@ -254,13 +581,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scripts_in_execution_order() {
+        let input = "---\nscript_user: root\nuser_script: rustup default stable\nroot_script: |\n  apt-get update\n---\nmake install\n";
+        let mixin = Mixin::try_from((Path::new("/tmp/test.mc"), to_reader(input))).unwrap();
+        let scripts = mixin
+            .scripts()
+            .into_iter()
+            .map(|x| (x.key, x.user, x.content))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scripts,
+            Vec::from([
+                (Some("root_script"), ScriptUser::Root, "apt-get update"),
+                (None, ScriptUser::Root, "make install"),
+                (
+                    Some("user_script"),
+                    ScriptUser::User,
+                    "rustup default stable"
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_artifact() {
+        let artifact = |source: &str, target: &str| Artifact {
+            source: source.into(),
+            target: target.into(),
+        };
+        assert_eq!(
+            "/opt/cmake".parse(),
+            Ok(artifact("/opt/cmake", "/opt/cmake"))
+        );
+        assert_eq!(
+            "/out/bin:/usr/local/bin".parse(),
+            Ok(artifact("/out/bin", "/usr/local/bin"))
+        );
+        assert!("a:b:c".parse::<Artifact>().is_err());
+        assert!(":b".parse::<Artifact>().is_err());
+    }
+
     #[test]
     fn missing_closing_dashes_errors() {
         let input = "---\nbase: ubuntu\n# missing closing dashes\necho hi\n";
         let reader = to_reader(input);
         let path = Path::new("/tmp/bad.mc");
         let err = Mixin::try_from((path, reader)).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(err, MixinError::MissingEnd { .. }));
     }
 
     #[test]
@@ -269,7 +637,74 @@ mod tests {
         let reader = to_reader(input);
         let path = Path::new("/tmp/empty.mc");
         let err = Mixin::try_from((path, reader)).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(err, MixinError::Empty { .. }));
+    }
+
+    #[test]
+    fn env_unset() {
+        let input = "---\nenv:\n  A: ~\n  B: null\n  C: value\n---\n";
+        let reader = to_reader(input);
+        let path = Path::new("/tmp/env.mc");
+        let mixin = Mixin::try_from((path, reader)).expect("should parse");
+        let env = mixin.yaml.env.unwrap();
+        assert_eq!(env["A"], None);
+        assert_eq!(env["B"], None);
+        assert_eq!(env["C"].as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn invalid_yaml_location() {
+        let input = "---\nbase: alpine\npublish:\n  - abc\n---\necho hi\n";
+        let reader = to_reader(input);
+        let path = Path::new("/tmp/invalid.mc");
+        let err = Mixin::try_from((path, reader)).unwrap_err();
+        assert_eq!(err.location(), (path, Some((4, 5))));
+        assert_eq!(
+            err.to_string(),
+            "invalid config yaml: publish[0]: Invalid publish format [<host_ip>:]<host_port>:<machine_prot>\n  \
+             --> /tmp/invalid.mc:4:5\n  |\n4 |   - abc\n  |     ^"
+        );
+    }
+
+    #[test]
+    fn yaml_message_strips_location() {
+        let err = serde_yaml::from_str::<MixinYaml>("install: [a\n").unwrap_err();
+        assert_eq!(
+            yaml_message(&err),
+            "did not find expected ',' or ']', while parsing a flow sequence"
+        );
+    }
+
+    #[test]
+    fn included_error_chain() {
+        let err = MixinError::Included {
+            parent: "/tmp/a.yaml".into(),
+            source: Box::new(MixinError::Empty {
+                path: "/tmp/b.yaml".into(),
+            }),
+        };
+        assert_eq!(err.location(), (Path::new("/tmp/b.yaml"), None));
+        assert_eq!(
+            err.to_string(),
+            "config was empty\n --> /tmp/b.yaml\n included from /tmp/a.yaml"
+        );
+    }
+
+    #[test]
+    fn nested_included_error_chain() {
+        let err = MixinError::Included {
+            parent: "/tmp/a.yaml".into(),
+            source: Box::new(MixinError::Included {
+                parent: "/tmp/b.yaml".into(),
+                source: Box::new(MixinError::Empty {
+                    path: "/tmp/c.yaml".into(),
+                }),
+            }),
+        };
+        assert_eq!(
+            err.to_string(),
+            "config was empty\n --> /tmp/c.yaml\n included from /tmp/b.yaml\n included from /tmp/a.yaml"
+        );
     }
 
     #[test]
@@ -1,9 +1,21 @@
-use crate::config::Mixin;
-use crate::docker::{Command, Dockerfile, User};
+use crate::config::{
+    Artifact, CommandLine, Hooks, Mixin, Script, ScriptUser, Service, Stage, WaitFor, glob_match,
+};
+use crate::docker::{
+    Cache, Command, Condition, Dockerfile, Sidecar, SidecarImage, User, Wait, http_target,
+};
 use derive_more::{Display, Error};
-use std::fs;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
+use std::{fs, io};
+
+/// Where scripts of the mixins are written to in the image
+const SCRIPT_DIR: &str = "/usr/local/share/mc2";
+
+/// Time a `wait_for:` condition gets if it defines no timeout
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Error, Display, Debug)]
 pub enum ConversionError {
@@ -13,8 +25,40 @@ pub enum ConversionError {
     NoBase,
     #[display("Invalid base: {}", _0)]
     UnknownBase(#[error(not(source))] String),
+    #[display("Invalid environment in {}: {}", path.display(), source)]
+    Env { path: PathBuf, source: io::Error },
+    #[display("Build stage '{}' is defined more than once", _0)]
+    DuplicateStage(#[error(not(source))] String),
+    #[display("'artifacts:' in {} requires a 'stage:'", _0.display())]
+    NoStage(#[error(not(source))] PathBuf),
+    #[display("Service '{}' needs either 'image:' or 'machine:'", _0)]
+    InvalidService(#[error(not(source))] String),
+    #[display(
+        "Service '{}' runs a machine and needs a 'command:', bash exits without a terminal",
+        _0
+    )]
+    NoServiceCommand(#[error(not(source))] String),
+    #[display(
+        "'wait_for:' in {} needs exactly one of tcp, http (an http:// URL), file or command",
+        _0.display()
+    )]
+    InvalidWaitFor(#[error(not(source))] PathBuf),
+    #[display("Invalid Dockerfile: {}", _0)]
+    Dockerfile(io::Error),
+}
+
+/// Options of the image build that do not come from the toolchain itself
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Overrides `docker_socket:` of the toolchain
+    pub docker_socket: Option<bool>,
+    /// Adds BuildKit cache mounts for package managers and `cache_dirs:`
+    pub cache_mounts: bool,
+    /// Merges consecutive RUN commands of the package setup into one layer
+    pub squash_layers: bool,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum PackageManager {
     DNF,
     ZYPPER,
@@ -44,6 +88,123 @@ impl PackageManager {
         }
     }
 
+    /// Download caches worth keeping between builds, package lists stay in the image
+    const fn cache_dirs(&self) -> &'static [&'static str] {
+        match self {
+            PackageManager::DNF => &["/var/cache/dnf"],
+            PackageManager::ZYPPER => &["/var/cache/zypp/packages"],
+            PackageManager::PACMAN => &["/var/cache/pacman/pkg"],
+            PackageManager::APT => &["/var/cache/apt"],
+            PackageManager::APK => &["/var/cache/apk"],
+        }
+    }
+
+    /// Makes the package manager keep downloaded packages in its cache
+    const fn keep_cache(&self) -> Option<&'static str> {
+        match self {
+            PackageManager::DNF => Some("echo keepcache=True >> /etc/dnf/dnf.conf"),
+            PackageManager::ZYPPER => Some("zypper modifyrepo --all --keep-packages"),
+            PackageManager::PACMAN => None,
+            PackageManager::APT => Some(
+                "rm -f /etc/apt/apt.conf.d/docker-clean && \
+                 echo 'Binary::apt::APT::Keep-Downloaded-Packages \"true\";' \
+                 > /etc/apt/apt.conf.d/keep-cache",
+            ),
+            PackageManager::APK => Some("ln -sf /var/cache/apk /etc/apk/cache"),
+        }
+    }
+
+    /// Removes downloads and, unless `keep_lists`, package lists so they do not stay in
+    /// the layer, caches in cache mounts are not part of the layer anyway
+    const fn cleanup(&self, cache_mounts: bool, keep_lists: bool) -> Option<&'static str> {
+        match (self, cache_mounts, keep_lists) {
+            (PackageManager::DNF, false, _) => Some("dnf clean all"),
+            (PackageManager::ZYPPER, false, _) => Some("zypper clean --all"),
+            (PackageManager::ZYPPER, true, _) => Some("zypper clean --metadata --raw-metadata"),
+            (PackageManager::PACMAN, false, _) => Some("rm -rf /var/cache/pacman/pkg/*"),
+            (PackageManager::APT, false, false) => Some("apt clean && rm -rf /var/lib/apt/lists/*"),
+            (PackageManager::APT, false, true) => Some("apt clean"),
+            (PackageManager::APT, true, false) => Some("rm -rf /var/lib/apt/lists/*"),
+            // The package index is kept next to the downloads
+            (PackageManager::APK, false, false) => Some("rm -rf /var/cache/apk/*"),
+            _ => None,
+        }
+    }
+
+    /// Fetches the package lists [`Self::cleanup`] removed
+    const fn refresh(&self) -> Option<&'static str> {
+        match self {
+            PackageManager::APT => Some("apt update"),
+            PackageManager::APK => Some("apk update"),
+            _ => None,
+        }
+    }
+
+    /// Whether `script` is an upgrade or install of this package manager
+    fn is_package_command(&self, script: &str) -> bool {
+        script.contains(self.upgrade()) || script.contains(self.install_prefix())
+    }
+
+    /// Appends the cleanup to upgrade and install commands. The package lists are
+    /// refreshed once before the first install unless an upgrade did so, and removed
+    /// only after the last command, unless `keep_lists`.
+    fn cleaned(
+        &self,
+        commands: Vec<Command>,
+        cache_mounts: bool,
+        keep_lists: bool,
+    ) -> Vec<Command> {
+        let last = commands
+            .iter()
+            .rposition(|x| matches!(x, Command::RUN(script) if self.is_package_command(script)));
+        let mut refreshed = false;
+        commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| match command {
+                Command::RUN(script) if self.is_package_command(&script) => {
+                    let mut parts = Vec::new();
+                    if !refreshed && script.starts_with(self.install_prefix()) {
+                        parts.extend(self.refresh());
+                    }
+                    refreshed = true;
+                    parts.push(script.as_str());
+                    parts.extend(self.cleanup(cache_mounts, keep_lists || Some(i) != last));
+                    Command::RUN(parts.join(" && "))
+                }
+                command => command,
+            })
+            .collect()
+    }
+
+    /// Mounts the package manager's caches into its upgrade and install commands, they
+    /// are shared by images of the same `base`
+    fn cached(&self, base: &str, command: Command) -> Command {
+        match command {
+            Command::RUN(script) if self.is_package_command(&script) => Command::CACHED {
+                caches: self
+                    .cache_dirs()
+                    .iter()
+                    .map(|dir| Cache {
+                        target: dir.to_string(),
+                        id: Some(format!("mc2-{}{}", base, dir)),
+                        owner: None,
+                    })
+                    .collect(),
+                script,
+            },
+            command => command,
+        }
+    }
+
+    /// Build arguments for running the package manager unattended
+    fn args(&self) -> Vec<Command> {
+        match self {
+            PackageManager::APT => Vec::from([Command::arg("DEBIAN_FRONTEND", "noninteractive")]),
+            _ => Vec::new(),
+        }
+    }
+
     fn defaults(&self) -> Vec<Command> {
         let mut result: Vec<Command> = Vec::from([Command::COMMENT("Ensure UTF-8 Support".into())]);
         match self {
@@ -59,27 +220,38 @@ impl PackageManager {
             PackageManager::PACMAN => {}
             PackageManager::APT => result.extend([
                 self.install(&["locales"]),
-                Command::arg("DEBIAN_FRONTEND", "noninteractive"),
                 Command::RUN("echo \'en_US.UTF-8 UTF-8\' >> /etc/locale.gen".to_string()),
                 Command::RUN("locale-gen".to_string()),
             ]),
             PackageManager::APK => {}
         };
-        result.extend([
-            Command::env("LANG", "en_US.UTF-8"),
-            Command::env("LANGUAGE", "en_US:en"),
-            Command::env("LC_ALL", "en_US.UTF-8"),
-        ]);
-
         result.extend([
             Command::COMMENT("Installing sudo and allow sudo for anyone".into()),
             self.install(&["sudo"]),
             Command::RUN("echo 'ALL ALL = (ALL) NOPASSWD: ALL' >> /etc/sudoers".into()),
         ]);
 
+        // Last, so RUN commands above can be squashed into one layer
+        result.extend([
+            Command::env("LANG", "en_US.UTF-8"),
+            Command::env("LANGUAGE", "en_US:en"),
+            Command::env("LC_ALL", "en_US.UTF-8"),
+        ]);
+
         result
     }
 
+    /// Package providing the docker command line client
+    const fn docker_cli(&self) -> &'static str {
+        match self {
+            PackageManager::DNF => "docker-cli",
+            PackageManager::ZYPPER => "docker",
+            PackageManager::PACMAN => "docker",
+            PackageManager::APT => "docker.io",
+            PackageManager::APK => "docker-cli",
+        }
+    }
+
     pub fn install<T: ToString>(&self, packages: &[T]) -> Command {
         let packages = packages
             .iter()
@@ -94,7 +266,7 @@ impl FromStr for PackageManager {
     type Err = ConversionError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let base = s.splitn(2, ':').nth(0).unwrap();
+        let base = s.split(':').next().unwrap();
         match base.to_lowercase().as_str() {
             "fedora" => Ok(PackageManager::DNF),
             "debian" => Ok(PackageManager::APT),
@@ -118,10 +290,182 @@ fn drop_conflicting(tool: &str, db: &str, id: u32, name: &str) -> String {
     )
 }
 
+/// Home directory of the user created in the image, matches the host user name
+pub fn container_home() -> PathBuf {
+    PathBuf::from("/home").join(users::get_current_username().unwrap())
+}
+
+/// Directory whose files are copied into the image as context files, only toolchains in a
+/// directory of their own like `.mc/<machine>/<machine>.yaml` have one
+pub fn context_dir(toolchain: &Path) -> Option<&Path> {
+    toolchain.parent().filter(|x| x.components().count() >= 2)
+}
+
+/// Patterns listed in `.mc2ignore`, matching context files are not copied into the image
+fn context_ignores(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join(".mc2ignore"))
+        .map(|content| {
+            content
+                .lines()
+                .map(|line| line.trim().trim_end_matches('/'))
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 impl TryFrom<&Mixin> for Dockerfile {
     type Error = ConversionError;
 
     fn try_from(value: &Mixin) -> Result<Self, Self::Error> {
+        Dockerfile::convert(value, &BuildOptions::default())
+    }
+}
+
+/// Condition of a `wait_for:` entry, `None` unless exactly one is set
+fn condition(mixin: &Mixin, wait_for: &WaitFor) -> Option<Condition> {
+    let mut conditions = Vec::new();
+    if let Some(address) = &wait_for.tcp {
+        conditions.push(Condition::Tcp(address.clone()));
+    }
+    if let Some(url) = &wait_for.http {
+        http_target(url)?;
+        conditions.push(Condition::Http(url.clone()));
+    }
+    if let Some(path) = &wait_for.file {
+        conditions.push(Condition::File(mixin.add_parent_path(path)));
+    }
+    if let Some(command) = &wait_for.command {
+        conditions.push(Condition::Command(command.clone()));
+    }
+    match conditions.len() {
+        1 => conditions.pop(),
+        _ => None,
+    }
+}
+
+/// What package manager commands of an image remove after themselves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cleanup {
+    /// Nothing, scripts of build stages may still install
+    Nothing,
+    /// Downloads only, scripts of the machine may still install
+    Downloads,
+    /// Downloads and package lists
+    All,
+}
+
+/// Merges consecutive RUN commands into one, comments between them become part of the
+/// script. Any other command ends the merged RUN.
+fn squash(commands: Vec<Command>) -> Vec<Command> {
+    let mut result = Vec::new();
+    let mut comments: Vec<String> = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut caches: Vec<Cache> = Vec::new();
+    let mut merged = 0;
+    let flush = |result: &mut Vec<Command>,
+                 lines: &mut Vec<String>,
+                 caches: &mut Vec<Cache>,
+                 merged: &mut usize| {
+        let mut script = lines.join("\n");
+        if *merged > 1 {
+            script = format!("set -e\n{}", script);
+        }
+        match caches.is_empty() {
+            true if !lines.is_empty() => result.push(Command::RUN(script)),
+            true => {}
+            false => result.push(Command::CACHED {
+                caches: std::mem::take(caches),
+                script,
+            }),
+        }
+        lines.clear();
+        *merged = 0;
+    };
+    for command in commands {
+        let (script, command_caches) = match command {
+            Command::COMMENT(comment) => {
+                comments.push(comment);
+                continue;
+            }
+            Command::RUN(script) => (script, Vec::new()),
+            Command::CACHED {
+                caches: command_caches,
+                script,
+            } => (script, command_caches),
+            command => {
+                flush(&mut result, &mut lines, &mut caches, &mut merged);
+                result.extend(comments.drain(..).map(Command::COMMENT));
+                result.push(command);
+                continue;
+            }
+        };
+        match lines.is_empty() {
+            true => result.extend(comments.drain(..).map(Command::COMMENT)),
+            false => lines.extend(comments.drain(..).map(|x| format!("# {}", x))),
+        }
+        lines.push(script);
+        merged += 1;
+        for cache in command_caches {
+            if !caches.contains(&cache) {
+                caches.push(cache);
+            }
+        }
+    }
+    flush(&mut result, &mut lines, &mut caches, &mut merged);
+    result.extend(comments.drain(..).map(Command::COMMENT));
+    result
+}
+
+/// Stage name derived from the mixin's file name, stage names are lowercase words
+fn stage_name(path: &Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = stem
+        .to_lowercase()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "-_.".contains(c) {
+            true => c,
+            false => '-',
+        })
+        .collect::<String>();
+    match name.starts_with(|c: char| c.is_ascii_lowercase()) {
+        true => name,
+        false => format!("stage-{}", name),
+    }
+}
+
+/// Shell script running the `on_start` hooks, the command given as arguments and the
+/// `on_exit` hooks. Without `on_exit` hooks the command replaces the script.
+fn entrypoint_wrapper(on_start: &[String], on_exit: &[String]) -> String {
+    let mut script = String::from("#!/bin/sh\n");
+    for hook in on_start {
+        script.push_str(&format!("{} || exit $?\n", hook));
+    }
+    if on_exit.is_empty() {
+        script.push_str("exec \"$@\"\n");
+        return script;
+    }
+    // Asynchronous commands get /dev/null as stdin unless it is redirected explicitly
+    script.push_str(
+        "exec 3<&0\n\
+         \"$@\" <&3 3<&- &\n\
+         child=$!\n\
+         exec 3<&-\n\
+         for signal in INT TERM HUP; do trap \"kill -s $signal $child 2>/dev/null\" $signal; done\n\
+         wait $child\n\
+         status=$?\n\
+         while kill -0 $child 2>/dev/null; do wait $child; status=$?; done\n",
+    );
+    for hook in on_exit {
+        script.push_str(&format!("{}\n", hook));
+    }
+    script.push_str("exit $status\n");
+    script
+}
+
+impl Dockerfile {
+    pub fn convert(value: &Mixin, options: &BuildOptions) -> Result<Self, ConversionError> {
         // Flatten mixins
         let mut mixins: Vec<&Mixin> = Vec::from_iter(&value.children);
         mixins.push(value);
@@ -131,7 +475,22 @@ impl TryFrom<&Mixin> for Dockerfile {
         // Process mixins and remove duplicates
         let mut from_file: Option<&Mixin> = None;
         let mut packages: Vec<(&Mixin, Vec<String>)> = Vec::new();
-        let mut scripts: Vec<(&Mixin, &String)> = Vec::new();
+        let mut scripts: Vec<(&Mixin, Script)> = Vec::new();
+        // Later mixins override earlier ones, so the toolchain file wins
+        let mut workdir: Option<&String> = None;
+        let mut entrypoint: Option<&CommandLine> = None;
+        let mut shell: Option<&Vec<String>> = None;
+        let mut healthcheck: Option<&CommandLine> = None;
+        let mut labels: BTreeMap<&String, &String> = BTreeMap::new();
+        let mut hooks: Vec<&Hooks> = Vec::new();
+        let mut stages: Vec<(String, &Stage)> = Vec::new();
+        let mut artifacts: Vec<(String, &Artifact)> = Vec::new();
+        let mut cache_dirs: Vec<String> = Vec::new();
+        let mut expose: Vec<u16> = Vec::new();
+        let mut services: BTreeMap<&String, (&Mixin, &Service)> = BTreeMap::new();
+        let docker_socket = options
+            .docker_socket
+            .unwrap_or(mixins.iter().any(|x| x.yaml.docker_socket == Some(true)));
         for mixin in mixins {
             if mixin.yaml.base.is_some() {
                 if let Some(from_file) = from_file {
@@ -147,8 +506,7 @@ impl TryFrom<&Mixin> for Dockerfile {
             for package_name in mixin.yaml.install.iter().flatten() {
                 if !packages
                     .iter()
-                    .map(|x| x.1.iter().any(|y| y == package_name))
-                    .any(|x| x)
+                    .any(|x| x.1.iter().any(|y| y == package_name))
                 {
                     l_packages.push(package_name.clone());
                 }
@@ -156,12 +514,59 @@ impl TryFrom<&Mixin> for Dockerfile {
             if !l_packages.is_empty() {
                 packages.push((mixin, l_packages))
             }
-            if let Some(script) = &mixin.script {
-                scripts.push((mixin, script));
-            }
+            scripts.extend(mixin.scripts().into_iter().map(|x| (mixin, x)));
 
             if let Some(publish) = &mixin.yaml.publish {
                 dockerfile.add_publishes(publish.iter());
+                for port in publish.iter().map(|x| x.machine_port) {
+                    if !expose.contains(&port) {
+                        expose.push(port);
+                    }
+                }
+            }
+            workdir = mixin.yaml.workdir.as_ref().or(workdir);
+            entrypoint = mixin.yaml.entrypoint.as_ref().or(entrypoint);
+            shell = mixin.yaml.shell.as_ref().or(shell);
+            healthcheck = mixin.yaml.healthcheck.as_ref().or(healthcheck);
+            labels.extend(mixin.yaml.labels.iter().flatten());
+            hooks.extend(&mixin.yaml.hooks);
+            for dir in mixin.yaml.cache_dirs.iter().flatten() {
+                let dir = match dir.strip_prefix("~/") {
+                    Some(dir) => container_home().join(dir).to_string_lossy().to_string(),
+                    None => dir.clone(),
+                };
+                if !cache_dirs.contains(&dir) {
+                    cache_dirs.push(dir);
+                }
+            }
+            if let Some(stage) = &mixin.yaml.stage {
+                let name = stage
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| stage_name(&mixin.path));
+                if stages.iter().any(|x| x.0 == name) {
+                    return Err(ConversionError::DuplicateStage(name));
+                }
+                for artifact in mixin.yaml.artifacts.iter().flatten() {
+                    artifacts.push((name.clone(), artifact));
+                }
+                stages.push((name, stage));
+            } else if mixin.yaml.artifacts.is_some() {
+                return Err(ConversionError::NoStage(mixin.path.clone()));
+            }
+            for (name, service) in mixin.yaml.services.iter().flatten() {
+                services.insert(name, (mixin, service));
+            }
+            for wait_for in mixin.yaml.wait_for.iter().flatten() {
+                let condition = condition(mixin, wait_for)
+                    .ok_or_else(|| ConversionError::InvalidWaitFor(mixin.path.clone()))?;
+                dockerfile.add_wait(Wait {
+                    condition,
+                    timeout: wait_for
+                        .timeout
+                        .map(Duration::from_secs)
+                        .unwrap_or(WAIT_TIMEOUT),
+                });
             }
 
             if let Some(volume) = &mixin.yaml.volume {
@@ -178,97 +583,538 @@ impl TryFrom<&Mixin> for Dockerfile {
                 );
             }
 
-            if let Some(env) = &mixin.yaml.env {
-                for (k, v) in env {
-                    dockerfile.add_env(k, v);
-                }
+            let env = mixin.resolve_env().map_err(|source| ConversionError::Env {
+                path: mixin.path.clone(),
+                source,
+            })?;
+            for entry in env {
+                dockerfile.set_env_entry(&entry);
             }
         }
 
         let Some(from) = &from_file else {
             return Err(ConversionError::NoBase);
         };
+        for (name, (mixin, service)) in services {
+            let image = match (&service.image, &service.machine) {
+                (Some(image), None) => SidecarImage::Image(image.clone()),
+                (None, Some(_)) if service.command.is_none() => {
+                    return Err(ConversionError::NoServiceCommand(name.clone()));
+                }
+                (None, Some(machine)) => SidecarImage::Machine(machine.clone()),
+                _ => return Err(ConversionError::InvalidService(name.clone())),
+            };
+            let env = BTreeMap::from_iter(service.env.iter().flatten());
+            dockerfile.sidecars_mut().push(Sidecar {
+                name: name.clone(),
+                image,
+                command: service
+                    .command
+                    .as_ref()
+                    .map(CommandLine::exec_form)
+                    .unwrap_or_default(),
+                env: env
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                publish: service.publish.clone().unwrap_or_default(),
+                volumes: service
+                    .volume
+                    .iter()
+                    .flatten()
+                    .map(|volume| {
+                        let mut volume = volume.clone();
+                        volume.host_path = mixin.add_parent_path(&volume.host_path);
+                        volume
+                    })
+                    .collect(),
+            });
+        }
         let from = from.yaml.base.as_ref().unwrap().clone();
         let package_manager = PackageManager::from_str(&from)?;
 
-        dockerfile.add(Command::FROM(from));
-
-        dockerfile.add(Command::COMMENT(
-            "Update outdated default dependencies".into(),
-        ));
-        dockerfile.add(Command::RUN(package_manager.upgrade().to_string()));
-        dockerfile.add_all(package_manager.defaults());
+        let has_stages = !stages.is_empty();
+        // Package manager commands of an image with cleanup, cache mounts and squashed
+        // layers as configured
+        let package_commands = |package_manager: &PackageManager,
+                                base: &str,
+                                commands: Vec<_>,
+                                cleanup: Cleanup| {
+            let commands = match cleanup {
+                Cleanup::Nothing => commands,
+                Cleanup::Downloads => package_manager.cleaned(commands, options.cache_mounts, true),
+                Cleanup::All => package_manager.cleaned(commands, options.cache_mounts, false),
+            };
+            let commands = commands
+                .into_iter()
+                .map(|command| match options.cache_mounts {
+                    true => package_manager.cached(base, command),
+                    false => command,
+                })
+                .collect::<Vec<_>>();
+            match options.squash_layers {
+                true => squash(commands),
+                false => commands,
+            }
+        };
+        let keep_cache = |package_manager: &PackageManager| {
+            let keep_cache = package_manager
+                .keep_cache()
+                .filter(|_| options.cache_mounts);
+            keep_cache.map(|x| Command::RUN(x.into()))
+        };
 
         let gid = users::get_current_gid();
         let gname = users::get_current_groupname().unwrap();
-        let gname = gname.display();
+        let gname = gname.display().to_string();
         let uid = users::get_current_uid();
         let uname = users::get_current_username().unwrap();
-        let uname = uname.display();
+        let uname = uname.display().to_string();
+        let home = container_home();
+        let home = home.display();
 
-        for (mixin, package_set) in &packages {
-            dockerfile.add(Command::COMMENT(format!(
-                "Installs from: {}",
-                mixin.path.display()
-            )));
-            dockerfile.add(package_manager.install(package_set));
-        }
+        // Everything not depending on the machine goes into an image shared by all
+        // machines on the same base
+        let mut base = Dockerfile::new_base();
+        base.add(Command::FROM {
+            image: from.clone(),
+            stage: None,
+        });
+        base.add(Command::COMMENT(
+            "Update outdated default dependencies".into(),
+        ));
+        base.add_all(package_manager.args());
+        let mut commands: Vec<_> = keep_cache(&package_manager).into_iter().collect();
+        commands.push(Command::RUN(package_manager.upgrade().to_string()));
+        commands.extend(package_manager.defaults());
+        base.add_all(package_commands(
+            &package_manager,
+            &from,
+            commands,
+            Cleanup::All,
+        ));
 
-        dockerfile.add(Command::COMMENT("Configure user".into()));
+        base.add(Command::COMMENT("Configure user".into()));
         // Drop any account the base image ships at our uid/gid (e.g. Ubuntu's
         // 'ubuntu' at 1000) before recreating ours. Delete the user before the
         // group, since groupdel refuses to remove a user's primary group.
-        let uname = uname.to_string();
-        let gname = gname.to_string();
-        dockerfile.add(Command::RUN(format!(
+        base.add(Command::RUN(format!(
             "{}; {}; \
              groupadd --gid {gid} {gname} && \
-             useradd --gid {gid} --uid {uid} --home /home/{uname} {uname}",
+             useradd --gid {gid} --uid {uid} --home {home} {uname}",
             drop_conflicting("userdel -r", "passwd", uid, &uname),
             drop_conflicting("groupdel", "group", gid, &gname),
         )));
-        dockerfile.add(Command::RUN(format!("mkdir -p /home/{}", uname)));
-        dockerfile.add(Command::RUN(format!(
-            "chown {}:{} /home/{}",
-            uid, gid, uname
-        )));
-        dockerfile.add(Command::USER(User {
+        // Pre-create directories forwards mount into, docker would create them owned by root
+        base.add(Command::RUN(format!("mkdir -p {home}/.ssh {home}/.config")));
+        base.add(Command::RUN(format!("chown -R {}:{} {}", uid, gid, home)));
+        let base_tag = base.tag().map_err(ConversionError::Dockerfile)?;
+        dockerfile.set_base(base);
+
+        for (name, stage) in stages {
+            let package_manager = PackageManager::from_str(&stage.base)?;
+            dockerfile.add(Command::COMMENT(format!("Build stage {}", name)));
+            dockerfile.add(Command::FROM {
+                image: stage.base.clone(),
+                stage: Some(name.clone()),
+            });
+            dockerfile.add_all(keep_cache(&package_manager));
+            let mut commands = Vec::from([Command::RUN(package_manager.upgrade().to_string())]);
+            if let Some(install) = &stage.install {
+                commands.push(package_manager.install(install));
+            }
+            dockerfile.add_all(package_commands(
+                &package_manager,
+                &stage.base,
+                commands,
+                Cleanup::Nothing,
+            ));
+            if let Some(script) = &stage.script {
+                let path = format!("{}/stage-script", SCRIPT_DIR);
+                dockerfile.add(Command::FILE {
+                    path: path.clone(),
+                    content: script.clone(),
+                    executable: true,
+                });
+                dockerfile.add(Command::RUN(path));
+            }
+        }
+
+        if has_stages {
+            dockerfile.add(Command::COMMENT("Image".into()));
+        }
+        dockerfile.add(Command::FROM {
+            image: base_tag,
+            stage: None,
+        });
+        for (key, value) in labels {
+            dockerfile.add(Command::LABEL(key.clone(), value.clone()));
+        }
+        // Build arguments are not inherited from the base image
+        dockerfile.add_all(package_manager.args());
+
+        let mut commands = Vec::new();
+        for (mixin, package_set) in &packages {
+            commands.push(Command::COMMENT(format!(
+                "Installs from: {}",
+                mixin.path.display()
+            )));
+            commands.push(package_manager.install(package_set));
+        }
+        if docker_socket {
+            commands.push(Command::COMMENT("Docker CLI for docker_socket".into()));
+            commands.push(package_manager.install(&[package_manager.docker_cli()]));
+        }
+        dockerfile.add_all(package_commands(
+            &package_manager,
+            &from,
+            commands,
+            Cleanup::Downloads,
+        ));
+
+        if !artifacts.is_empty() {
+            dockerfile.add(Command::COMMENT("Artifacts of build stages".into()));
+        }
+        for (stage, artifact) in artifacts {
+            dockerfile.add(Command::COPY {
+                from: Some(stage),
+                src: artifact.source.to_string_lossy().to_string(),
+                dst: artifact.target.to_string_lossy().to_string(),
+            });
+        }
+
+        // Docker would create the parents of cache mounts owned by root
+        let in_home = |x: &&String| Path::new(x).starts_with(container_home());
+        let home_caches = cache_dirs
+            .iter()
+            .filter(in_home)
+            .cloned()
+            .collect::<Vec<_>>();
+        if options.cache_mounts && !home_caches.is_empty() {
+            dockerfile.add(Command::RUN(format!(
+                "mkdir -p {} && chown -R {}:{} {}",
+                home_caches.join(" "),
+                uid,
+                gid,
+                home
+            )));
+        }
+        // The socket is looked up on docker run, which adds its group to the user
+        dockerfile.set_docker_socket(docker_socket);
+        let user = User {
             uid: uid as u16,
             gid: Some(gid as u16),
-        }));
+        };
+        dockerfile.set_user(uname.clone());
 
-        if let Some(parent_dir) = value.path.parent()
-            && parent_dir.components().count() >= 2
-        {
+        if let Some(parent_dir) = context_dir(&value.path) {
+            let ignored = context_ignores(parent_dir);
             let dirs = fs::read_dir(parent_dir).unwrap();
             let files = dirs
                 .filter_map(|x| match x {
-                    Ok(x) if !x.file_name().to_string_lossy().starts_with(".") => Some(x.path()),
+                    Ok(x) if !x.file_name().to_string_lossy().starts_with(".") => Some(x),
                     _ => None,
                 })
+                .filter(|x| {
+                    let name = x.file_name().to_string_lossy().to_string();
+                    !ignored.iter().any(|pattern| glob_match(pattern, &name))
+                })
+                .map(|x| x.path())
                 .collect::<Vec<_>>();
             if !files.is_empty() {
                 dockerfile.add(Command::COMMENT("Adding context files".into()));
             }
             for file in files {
-                dockerfile.add(Command::COPY(
-                    file.to_string_lossy().to_string(),
-                    format!("/{}", file.file_name().unwrap().to_string_lossy()),
-                ));
+                dockerfile.add(Command::COPY {
+                    from: None,
+                    src: file.to_string_lossy().to_string(),
+                    dst: format!("/{}", file.file_name().unwrap().to_string_lossy()),
+                });
             }
         }
 
-        for (mixin, script) in scripts {
+        if let Some(shell) = shell {
+            dockerfile.add(Command::SHELL(shell.clone()));
+        }
+        if let Some(workdir) = workdir {
+            dockerfile.add(Command::WORKDIR(workdir.clone()));
+        }
+        // The base image's setup runs as root, the image ends up as the user
+        let mut current = ScriptUser::Root;
+        for (i, (mixin, script)) in scripts.into_iter().enumerate() {
             dockerfile.add(Command::COMMENT(format!(
-                "Exec script from: {}",
+                "Exec {} from: {}",
+                script.key.unwrap_or("script"),
                 mixin.path.display()
             )));
-            dockerfile.add(Command::RUN(format!("<<EOR\n/bin/sh -c {}\nEOR", script)));
+            if script.user != current {
+                dockerfile.add(Command::USER(match script.user {
+                    ScriptUser::Root => User { uid: 0, gid: None },
+                    ScriptUser::User => user.clone(),
+                }));
+                current = script.user;
+            }
+            // Executed as file so the kernel honors its shebang, the shell runs it otherwise
+            let path = format!("{}/script-{}", SCRIPT_DIR, i);
+            dockerfile.add(Command::FILE {
+                path: path.clone(),
+                content: script.content.to_string(),
+                executable: true,
+            });
+            let run = match &mixin.yaml.script_shell {
+                Some(shell) if !script.content.starts_with("#!") => format!("{} {}", shell, path),
+                _ => path,
+            };
+            match options.cache_mounts && !cache_dirs.is_empty() {
+                true => dockerfile.add(Command::CACHED {
+                    caches: cache_dirs
+                        .iter()
+                        .map(|dir| Cache {
+                            target: dir.clone(),
+                            id: None,
+                            owner: Some(user.clone()),
+                        })
+                        .collect(),
+                    script: run,
+                }),
+                false => dockerfile.add(Command::RUN(run)),
+            }
+        }
+        if current != ScriptUser::User {
+            dockerfile.add(Command::USER(user));
         }
 
-        dockerfile.add(Command::COMMENT("Exec bash as entrypoint".into()));
-        dockerfile.add(Command::RUN("/usr/bin/env bash".into()));
+        for port in expose {
+            dockerfile.add(Command::EXPOSE(port));
+        }
+        if let Some(healthcheck) = healthcheck {
+            dockerfile.add(Command::HEALTHCHECK(Some(healthcheck.exec_form())));
+        }
+        let mut entrypoint = entrypoint.map(CommandLine::entrypoint_form);
+        if !hooks.is_empty() {
+            dockerfile.add(Command::COMMENT("Lifecycle hooks".into()));
+            let mut add_hooks = |name: &str, scripts: Vec<&String>| {
+                let mut paths = Vec::new();
+                for (i, script) in scripts.into_iter().enumerate() {
+                    let path = format!("{}/hooks/{}-{}", SCRIPT_DIR, name, i);
+                    dockerfile.add(Command::FILE {
+                        path: path.clone(),
+                        content: script.clone(),
+                        executable: true,
+                    });
+                    paths.push(path);
+                }
+                paths
+            };
+            let on_start = add_hooks("on_start", hooks.iter().flat_map(|x| &x.on_start).collect());
+            let on_enter = add_hooks("on_enter", hooks.iter().flat_map(|x| &x.on_enter).collect());
+            // Undo in reverse order of setup
+            let on_exit = add_hooks(
+                "on_exit",
+                hooks.iter().rev().flat_map(|x| &x.on_exit).collect(),
+            );
+            for path in on_enter {
+                dockerfile.add(Command::RUN(format!(
+                    "echo '. {}' >> {}/.bashrc",
+                    path, home
+                )));
+            }
+            if !on_start.is_empty() || !on_exit.is_empty() {
+                let wrapper = format!("{}/entrypoint", SCRIPT_DIR);
+                dockerfile.add(Command::FILE {
+                    path: wrapper.clone(),
+                    content: entrypoint_wrapper(&on_start, &on_exit),
+                    executable: true,
+                });
+                dockerfile.set_on_start(on_start);
+                let mut args = Vec::from([wrapper]);
+                args.extend(entrypoint.take().into_iter().flatten());
+                entrypoint = Some(args);
+            }
+        }
+        if let Some(entrypoint) = entrypoint {
+            dockerfile.add(Command::ENTRYPOINT(entrypoint));
+        }
+        dockerfile.add(Command::COMMENT("Exec bash by default".into()));
+        dockerfile.add(Command::CMD(Vec::from([
+            "/usr/bin/env".into(),
+            "bash".into(),
+        ])));
 
         Ok(dockerfile)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{self, Stdio};
+
+    #[test]
+    fn test_cached() {
+        let apt = PackageManager::APT;
+        let Command::CACHED { caches, script } = apt.cached("debian:13", apt.install(&["gcc"]))
+        else {
+            panic!("install should be cached");
+        };
+        assert_eq!(script, "apt install -y gcc");
+        assert_eq!(caches[0].target, "/var/cache/apt");
+        assert_eq!(caches[0].id.as_deref(), Some("mc2-debian:13/var/cache/apt"));
+        let locale_gen = Command::RUN("locale-gen".into());
+        assert_eq!(apt.cached("debian:13", locale_gen.clone()), locale_gen);
+    }
+
+    #[test]
+    fn test_cleaned() {
+        let apt = PackageManager::APT;
+        assert_eq!(
+            apt.cleaned(Vec::from([apt.install(&["gcc"])]), false, false),
+            [Command::RUN(
+                "apt update && apt install -y gcc && apt clean && rm -rf /var/lib/apt/lists/*"
+                    .into()
+            )]
+        );
+        // The lists are fetched once and removed after the last install
+        let locale_gen = Command::RUN("locale-gen".into());
+        assert_eq!(
+            apt.cleaned(
+                Vec::from([
+                    apt.install(&["gcc"]),
+                    locale_gen.clone(),
+                    apt.install(&["make"]),
+                ]),
+                false,
+                false
+            ),
+            [
+                Command::RUN("apt update && apt install -y gcc && apt clean".into()),
+                locale_gen.clone(),
+                Command::RUN(
+                    "apt install -y make && apt clean && rm -rf /var/lib/apt/lists/*".into()
+                ),
+            ]
+        );
+        // The upgrade already refreshed them
+        assert_eq!(
+            apt.cleaned(
+                Vec::from([Command::RUN(apt.upgrade().into()), apt.install(&["gcc"])]),
+                false,
+                true
+            ),
+            [
+                Command::RUN("apt update && apt upgrade -y && apt clean".into()),
+                Command::RUN("apt install -y gcc && apt clean".into()),
+            ]
+        );
+        let dnf = PackageManager::DNF;
+        assert_eq!(
+            dnf.cleaned(Vec::from([dnf.install(&["gcc"])]), true, false),
+            [dnf.install(&["gcc"])]
+        );
+    }
+
+    #[test]
+    fn test_squash() {
+        let cache = |target: &str| Cache {
+            target: target.into(),
+            id: None,
+            owner: None,
+        };
+        let squashed = squash(Vec::from([
+            Command::COMMENT("Upgrade".into()),
+            Command::RUN("a".into()),
+            Command::COMMENT("Install".into()),
+            Command::CACHED {
+                caches: Vec::from([cache("/var/cache/apt")]),
+                script: "b".into(),
+            },
+            Command::env("LANG", "C"),
+            Command::RUN("c".into()),
+        ]));
+        assert_eq!(
+            squashed,
+            Vec::from([
+                Command::COMMENT("Upgrade".into()),
+                Command::CACHED {
+                    caches: Vec::from([cache("/var/cache/apt")]),
+                    script: "set -e\na\n# Install\nb".into(),
+                },
+                Command::env("LANG", "C"),
+                Command::RUN("c".into()),
+            ])
+        );
+    }
+
+    fn convert(yaml: &str) -> Result<Dockerfile, ConversionError> {
+        let reader = io::BufReader::new(yaml.as_bytes());
+        let mixin = Mixin::try_from((Path::new("mc.yaml"), reader)).unwrap();
+        Dockerfile::convert(&mixin, &BuildOptions::default())
+    }
+
+    #[test]
+    fn test_machine_service() {
+        let yaml = "---\nbase: debian:trixie-slim\nservices:\n  api:\n    machine: api\n";
+        assert!(matches!(
+            convert(&format!("{}---\n", yaml)),
+            Err(ConversionError::NoServiceCommand(name)) if name == "api"
+        ));
+        let mut dockerfile = convert(&format!("{}    command: ./serve\n---\n", yaml)).unwrap();
+        let sidecar = &dockerfile.sidecars_mut()[0];
+        assert_eq!(sidecar.image, SidecarImage::Machine("api".into()));
+        assert_eq!(sidecar.command, ["/bin/sh", "-c", "./serve"]);
+    }
+
+    #[test]
+    fn test_docker_socket() {
+        // Converting never looks at the host's socket, docker run does
+        let yaml = "---\nbase: debian:trixie-slim\ndocker_socket: true\n---\n";
+        let dockerfile = convert(yaml).unwrap();
+        assert!(dockerfile.docker_socket());
+        let content = dockerfile.to_string();
+        assert!(content.contains("docker.io"));
+        assert!(!content.contains("groupadd"));
+    }
+
+    #[test]
+    fn test_stage_name() {
+        assert_eq!(stage_name(Path::new("/p/.mc/cmake.yaml")), "cmake");
+        assert_eq!(stage_name(Path::new("/p/Cross GCC.yaml")), "cross-gcc");
+        assert_eq!(stage_name(Path::new("/p/3d.yaml")), "stage-3d");
+    }
+
+    #[test]
+    fn test_entrypoint_wrapper() {
+        let run = |on_start: &[&str], on_exit: &[&str], command: &str| {
+            let hooks = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            let mut child = process::Command::new("sh")
+                .args(["-c", &entrypoint_wrapper(&hooks(on_start), &hooks(on_exit))])
+                .args(["sh", "sh", "-c", command])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            // Fails if the wrapper exited already
+            let _ = child.stdin.take().unwrap().write_all(b"input\n");
+            let output = child.wait_with_output().unwrap();
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            (stdout, output.status.code())
+        };
+        assert_eq!(
+            run(&["echo start"], &[], "echo cmd"),
+            ("start\ncmd\n".into(), Some(0))
+        );
+        assert_eq!(
+            run(&["echo start"], &["echo exit"], "echo cmd; exit 3"),
+            ("start\ncmd\nexit\n".into(), Some(3))
+        );
+        assert_eq!(
+            run(&["false"], &["echo exit"], "echo cmd"),
+            ("".into(), Some(1))
+        );
+        assert_eq!(
+            run(&[], &["true"], "read x; echo $x"),
+            ("input\n".into(), Some(0))
+        );
+    }
+}
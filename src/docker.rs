@@ -1,12 +1,26 @@
-use crate::config::{Publish, Volume};
-use derive_more::Display;
+mod command;
+mod sidecar;
+mod wait;
+
+use crate::config::{EnvEntry, Publish, Volume};
+use crate::forward::{self, Forward, Gui};
+use crate::signal;
+pub use command::*;
 use sha2::Digest;
+pub use sidecar::*;
 use std::fmt::{Display, Formatter};
-use std::io::{BufWriter, Cursor, ErrorKind, Write};
+use std::fs::File;
+use std::io::{BufWriter, Cursor, ErrorKind, IsTerminal, Write};
+use std::os::fd::AsFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::Stdio;
-use std::{env, io, process};
+use std::time::{Duration, Instant};
+use std::{env, io, process, thread};
+pub use wait::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub uid: u16,
     pub gid: Option<u16>,
@@ -22,36 +36,58 @@ impl Display for User {
     }
 }
 
-#[derive(Debug, Display, Clone)]
-pub enum Command {
-    #[display("FROM {}", _0)]
-    FROM(String),
-    #[display("# {}", _0)]
-    COMMENT(String),
-    #[display("CMD {}", _0)]
-    CMD(String),
-    #[display("ENV {}={}", _0, _1)]
-    ENV(String, String),
-    #[display("ARG {}={}", _0, _1)]
-    ARG(String, String),
-    #[display("RUN {}", _0)]
-    RUN(String),
-    #[display("USER {}", _0)]
-    USER(User),
-    #[display("COPY {} {}", _0, _1)]
-    COPY(String, String),
+/// Which of docker run's `-i` and `-t` are passed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Terminal {
+    /// Keep stdin attached (-i)
+    pub interactive: bool,
+    /// Allocate a pseudo terminal (-t)
+    pub tty: bool,
 }
 
-impl Command {
-    pub fn env<A: ToString + ?Sized, B: ToString + ?Sized>(a: &A, b: &B) -> Self {
-        Self::ENV(a.to_string(), b.to_string())
+impl Terminal {
+    /// Attaches stdin if it is a terminal, a pipe or a file and allocates a
+    /// terminal only if stdin and stdout are terminals
+    pub fn detect() -> Self {
+        let stdin = io::stdin();
+        let stdin_terminal = stdin.is_terminal();
+        let stdin_data = stdin
+            .as_fd()
+            .try_clone_to_owned()
+            .map(File::from)
+            .and_then(|x| x.metadata())
+            .is_ok_and(|x| x.file_type().is_fifo() || x.is_file());
+        Terminal {
+            interactive: stdin_terminal || stdin_data,
+            tty: stdin_terminal && io::stdout().is_terminal(),
+        }
     }
 
-    pub fn arg<A: ToString + ?Sized, B: ToString + ?Sized>(a: &A, b: &B) -> Self {
-        Self::ARG(a.to_string(), b.to_string())
+    pub fn args(&self) -> Vec<&'static str> {
+        match (self.interactive, self.tty) {
+            (true, true) => Vec::from(["-it"]),
+            (true, false) => Vec::from(["-i"]),
+            (false, true) => Vec::from(["-t"]),
+            (false, false) => Vec::new(),
+        }
     }
 }
 
+/// Time the container gets to stop after SIGTERM or SIGHUP before it is removed
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stage name of the base image in [`Dockerfile::write_standalone_to`]
+const BASE_STAGE: &str = "mc2-base";
+
+/// Where the value of an environment variable comes from if it is not literal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvOrigin {
+    /// Passed through from the host
+    Host,
+    /// Read from an env file
+    File(PathBuf),
+}
+
 #[derive(Debug)]
 pub struct Dockerfile {
     /// Dockerfile it self
@@ -60,8 +96,34 @@ pub struct Dockerfile {
     publish: Vec<Publish>,
     /// Volume (-v) added to docker run
     volumes: Vec<Volume>,
-    /// Environment (-e) added to docker run
+    /// Environment (-e) added to docker run, unique by key in order of definition
     env: Vec<(String, String)>,
+    /// Origin of the variables in `env` that are not literal
+    env_origins: Vec<(String, EnvOrigin)>,
+    /// Forward the host's ssh agent if one is running
+    ssh_agent: bool,
+    /// Mount the host's ~/.ssh/known_hosts read-only
+    ssh_known_hosts: bool,
+    /// Opt-in host resources forwarded on docker run
+    forwards: Vec<Forward>,
+    /// GUI forwards on docker run
+    gui: Gui,
+    /// Mount the host's docker socket on docker run
+    docker_socket: bool,
+    /// Runs an init process as PID 1 that reaps zombies
+    init: bool,
+    /// Tag of the image is `<tag_prefix>-<hash>`
+    tag_prefix: &'static str,
+    /// Image this one is built on, built first if it does not exist
+    base: Option<Box<Dockerfile>>,
+    /// Name of the user the image runs as
+    user: Option<String>,
+    /// Hooks run before the command in the image, the entrypoint runs them on docker run
+    on_start: Vec<String>,
+    /// Containers started next to this one on docker run
+    sidecars: Vec<Sidecar>,
+    /// Conditions awaited before docker run
+    waits: Vec<Wait>,
 }
 
 impl Dockerfile {
@@ -71,9 +133,74 @@ impl Dockerfile {
             publish: Vec::new(),
             volumes: Vec::new(),
             env: Vec::new(),
+            env_origins: Vec::new(),
+            ssh_agent: true,
+            ssh_known_hosts: false,
+            forwards: Vec::new(),
+            gui: Gui::default(),
+            docker_socket: false,
+            init: false,
+            tag_prefix: "mini-cross2",
+            base: None,
+            user: None,
+            on_start: Vec::new(),
+            sidecars: Vec::new(),
+            waits: Vec::new(),
         }
     }
 
+    /// Image shared by the machines building on it
+    pub fn new_base() -> Self {
+        Dockerfile {
+            tag_prefix: "mini-cross2-base",
+            ..Dockerfile::new()
+        }
+    }
+
+    pub fn base(&self) -> Option<&Dockerfile> {
+        self.base.as_deref()
+    }
+
+    pub fn set_base(&mut self, base: Dockerfile) {
+        self.base = Some(Box::new(base))
+    }
+
+    pub fn publishes(&self) -> &[Publish] {
+        &self.publish
+    }
+
+    pub fn volumes(&self) -> &[Volume] {
+        &self.volumes
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn set_user(&mut self, user: String) {
+        self.user = Some(user)
+    }
+
+    pub fn on_start(&self) -> &[String] {
+        &self.on_start
+    }
+
+    pub fn set_on_start(&mut self, on_start: Vec<String>) {
+        self.on_start = on_start
+    }
+
+    pub fn sidecars_mut(&mut self) -> &mut Vec<Sidecar> {
+        &mut self.sidecars
+    }
+
+    pub fn add_wait(&mut self, wait: Wait) {
+        self.waits.push(wait)
+    }
+
     pub fn add(&mut self, command: Command) {
         self.entries.push(command)
     }
@@ -90,40 +217,157 @@ impl Dockerfile {
         self.publish.extend(args.cloned())
     }
 
+    /// Sets an environment variable, replacing an earlier definition of the same key
     pub fn add_env(&mut self, k: &str, v: &str) {
-        self.env.push((k.to_string(), v.to_string()))
+        self.env_origins.retain(|(key, _)| key != k);
+        match self.env.iter_mut().find(|(key, _)| key == k) {
+            Some((_, value)) => *value = v.to_string(),
+            None => self.env.push((k.to_string(), v.to_string())),
+        }
+    }
+
+    /// Sets or, if `v` is `None`, unsets an environment variable
+    pub fn set_env(&mut self, k: &str, v: Option<&str>) {
+        match v {
+            Some(v) => self.add_env(k, v),
+            None => self.remove_env(k),
+        }
+    }
+
+    /// Sets or unsets a variable of a config, remembering where its value comes from
+    pub fn set_env_entry(&mut self, entry: &EnvEntry) {
+        self.set_env(&entry.key, entry.value.as_deref());
+        let origin = match (&entry.file, entry.host) {
+            (Some(path), _) => EnvOrigin::File(path.clone()),
+            (None, true) => EnvOrigin::Host,
+            (None, false) => return,
+        };
+        self.env_origins.push((entry.key.clone(), origin));
+    }
+
+    /// Unsets an environment variable defined earlier
+    pub fn remove_env(&mut self, k: &str) {
+        self.env.retain(|(key, _)| key != k);
+        self.env_origins.retain(|(key, _)| key != k);
+    }
+
+    /// Where the value of a variable comes from, `None` if it is literal
+    pub fn env_origin(&self, k: &str) -> Option<&EnvOrigin> {
+        self.env_origins
+            .iter()
+            .find_map(|(key, origin)| (key == k).then_some(origin))
+    }
+
+    pub fn set_ssh_agent(&mut self, enable: bool) {
+        self.ssh_agent = enable
+    }
+
+    pub fn set_ssh_known_hosts(&mut self, enable: bool) {
+        self.ssh_known_hosts = enable
+    }
+
+    pub fn add_forwards<'a, I: Iterator<Item = &'a Forward>>(&mut self, forwards: I) {
+        for forward in forwards {
+            if !self.forwards.contains(forward) {
+                self.forwards.push(*forward);
+            }
+        }
+    }
+
+    /// Overrides the GUI forwards `gui` defines
+    pub fn merge_gui(&mut self, gui: &Gui) {
+        self.gui.merge(gui)
+    }
+
+    pub fn disable_gui(&mut self) {
+        self.gui.disable()
+    }
+
+    pub fn init(&self) -> bool {
+        self.init
+    }
+
+    pub fn set_init(&mut self, enable: bool) {
+        self.init = enable
+    }
+
+    pub fn docker_socket(&self) -> bool {
+        self.docker_socket
+    }
+
+    pub fn set_docker_socket(&mut self, enable: bool) {
+        self.docker_socket = enable
     }
 
     pub fn write_to<T: Write>(&self, writer: &mut BufWriter<T>) -> io::Result<()> {
         for entry in self.entries.iter() {
+            entry.validate()?;
             if matches!(entry, Command::COMMENT(_)) {
-                write!(writer, "\n")?;
+                writeln!(writer)?;
             }
-            write!(writer, "{}\n", entry)?;
+            writeln!(writer, "{}", entry)?;
         }
         Ok(())
     }
 
-    pub fn hash(&self) -> String {
+    /// Writes a Dockerfile that builds the base image as first stage, so it can be built
+    /// without mc2
+    pub fn write_standalone_to<T: Write>(&self, writer: &mut BufWriter<T>) -> io::Result<()> {
+        let Some(base) = &self.base else {
+            return self.write_to(writer);
+        };
+        let base_tag = base.tag()?;
+        let mut standalone = Dockerfile::new();
+        standalone.add(Command::COMMENT("Base image".into()));
+        for entry in base.entries.iter() {
+            standalone.add(match entry {
+                Command::FROM { image, stage: None } => Command::FROM {
+                    image: image.clone(),
+                    stage: Some(BASE_STAGE.into()),
+                },
+                entry => entry.clone(),
+            });
+        }
+        for entry in self.entries.iter() {
+            standalone.add(match entry {
+                Command::FROM { image, stage } if *image == base_tag => Command::FROM {
+                    image: BASE_STAGE.into(),
+                    stage: stage.clone(),
+                },
+                entry => entry.clone(),
+            });
+        }
+        standalone.write_to(writer)
+    }
+
+    pub fn hash(&self) -> io::Result<String> {
+        let mut buf = Vec::new();
+        self.write_to(&mut BufWriter::new(&mut buf))?;
         let mut hasher = sha2::Sha256::new();
-        Digest::update(&mut hasher, self.to_string().as_bytes());
-        hex::encode(hasher.finalize())
+        Digest::update(&mut hasher, &buf);
+        Ok(hex::encode(hasher.finalize()))
     }
 
-    pub fn tag(&self) -> String {
-        format!("mini-cross2-{}", self.hash())
+    pub fn tag(&self) -> io::Result<String> {
+        Ok(format!("{}-{}", self.tag_prefix, self.hash()?))
     }
 
     pub fn exists(&self) -> io::Result<bool> {
-        let tag = self.tag();
+        let tag = self.tag()?;
         let output = process::Command::new("docker")
             .args(["images", "-q", &tag])
             .output()?;
         Ok(!output.stdout.is_empty() && output.status.success())
     }
 
-    pub fn build(&self) -> io::Result<()> {
-        let tag = self.tag();
+    /// Builds the image, the base image is built first if it does not exist or `force` is set
+    pub fn build(&self, force: bool) -> io::Result<()> {
+        if let Some(base) = &self.base
+            && (force || !base.exists()?)
+        {
+            base.build(force)?;
+        }
+        let tag = self.tag()?;
         // Build image
         let mut build_progress = process::Command::new("docker")
             .args(["image", "build", "--tag", &tag, "-f", "-", "."])
@@ -142,64 +386,137 @@ impl Dockerfile {
         Ok(())
     }
 
-    pub fn run(&self, cmd: &Vec<String>, stdio_enable: bool) -> io::Result<()> {
-        let tag = self.tag();
+    /// Runs the image and returns the exit code of the command. Forwards SIGINT, SIGTERM
+    /// and SIGHUP sent to mc2, the container is removed if it does not stop in time.
+    pub fn run(&self, cmd: &Vec<String>, terminal: Terminal) -> io::Result<i32> {
+        signal::install()?;
+        let tag = self.tag()?;
 
-        let stdio = if stdio_enable {
-            Vec::from(["-it"])
-        } else {
-            Vec::new()
-        };
         let workdir = env::current_dir()?;
-        let display_args = env::var("DISPLAY")
-            .ok()
-            .map(|display| {
-                [
-                    "-e".to_string(),
-                    format!("DISPLAY={}", display),
-                    "-v".to_string(),
-                    "/tmp/.X11-unix:/tmp/.X11-unix".to_string(),
-                ]
-                .to_vec()
-            })
-            .unwrap_or_default();
         let publish = self
             .publish
             .iter()
-            .map(|x| ["-p".into(), x.to_string()])
-            .flatten()
+            .flat_map(|x| ["-p".into(), x.to_string()])
             .collect::<Vec<String>>();
         let volumes = self
             .volumes
             .iter()
-            .map(|x| ["-v".into(), x.to_string()])
-            .flatten()
+            .flat_map(|x| ["-v".into(), x.to_string()])
             .collect::<Vec<String>>();
         let envs = self
             .env
             .iter()
-            .map(|(k, v)| ["-e".into(), format!("{}={}", k, v)])
-            .flatten()
+            .flat_map(|(k, v)| ["-e".into(), format!("{}={}", k, v)])
             .collect::<Vec<String>>();
-        process::Command::new("docker")
+        let docker_socket = match self.docker_socket {
+            true => forward::docker_socket_args()?,
+            false => Vec::new(),
+        };
+        let name = self.container_name()?;
+        // Removed when the run ends
+        let sidecars = match self.sidecars.is_empty() {
+            true => None,
+            false => Some(Sidecars::start(&self.sidecars, &name)?),
+        };
+        let hosts = match &sidecars {
+            Some(sidecars) => sidecars.addresses()?,
+            None => Vec::new(),
+        };
+        wait_for(&self.waits, &hosts)?;
+        let mut child = process::Command::new("docker")
             .args([
                 "run",
                 "--rm",
+                "--name",
+                &name,
                 "-v",
                 &format!("{}:{}", workdir.display(), workdir.display()),
                 "-w",
                 &workdir.to_string_lossy(),
             ])
-            .args(stdio)
-            .args(display_args)
+            .args(terminal.args())
+            .args(if self.init {
+                Vec::from(["--init"])
+            } else {
+                Vec::new()
+            })
+            .args(self.gui.args())
+            .args(if self.ssh_agent {
+                forward::ssh_agent(self.ssh_known_hosts)
+            } else {
+                Vec::new()
+            })
+            .args(self.forwards.iter().flat_map(Forward::args))
+            .args(docker_socket)
+            .args(
+                sidecars
+                    .iter()
+                    .flat_map(|x| ["--network".to_string(), x.network().to_string()]),
+            )
             .args(publish)
             .args(volumes)
             .args(envs)
             .arg(&tag)
             .args(cmd)
-            .status()?;
-        Ok(())
+            .spawn()?;
+
+        let mut deadline: Option<Instant> = None;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if let Some((signal, from_user)) = signal::take() {
+                if from_user {
+                    // docker run proxies signals it receives to the container
+                    signal::send(child.id(), signal)?;
+                }
+                if signal != libc::SIGINT {
+                    deadline.get_or_insert(Instant::now() + STOP_TIMEOUT);
+                }
+            }
+            if deadline.is_some_and(|x| Instant::now() >= x) {
+                // The container ignored the signal
+                remove_container(&name);
+                deadline = None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        // docker run may have been killed without its --rm ever firing
+        if !status.success() {
+            remove_container(&name);
+        }
+        Ok(status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()))
+    }
+
+    /// Name of the container started by [`Dockerfile::run`], unique per mc2 process
+    pub fn container_name(&self) -> io::Result<String> {
+        Ok(format!("mc2-{}-{}", &self.hash()?[..12], process::id()))
+    }
+}
+
+/// Whether `docker build` uses BuildKit, cache mounts need it
+pub fn buildkit_available() -> bool {
+    if env::var("DOCKER_BUILDKIT").is_ok_and(|x| x == "0") {
+        return false;
     }
+    process::Command::new("docker")
+        .args(["buildx", "version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+/// Force removes a container, it may already be gone
+fn remove_container(name: &str) {
+    let _ = process::Command::new("docker")
+        .args(["rm", "--force", name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
 }
 
 impl Display for Dockerfile {
@@ -207,8 +524,82 @@ impl Display for Dockerfile {
         let mut buf = Cursor::new(Vec::new());
         {
             let mut buf = BufWriter::new(&mut buf);
-            self.write_to(&mut buf).unwrap();
+            self.write_to(&mut buf).map_err(|_| std::fmt::Error)?;
         }
         write!(f, "{}", String::from_utf8(buf.into_inner()).unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminal_args() {
+        let terminal = |interactive, tty| Terminal { interactive, tty }.args();
+        assert_eq!(terminal(true, true), ["-it"]);
+        assert_eq!(terminal(true, false), ["-i"]);
+        assert_eq!(terminal(false, true), ["-t"]);
+        assert!(terminal(false, false).is_empty());
+    }
+
+    #[test]
+    fn test_tag() {
+        let mut base = Dockerfile::new_base();
+        base.add(Command::FROM {
+            image: "debian:trixie-slim".into(),
+            stage: None,
+        });
+        let base_tag = base.tag().unwrap();
+        assert!(base_tag.starts_with("mini-cross2-base-"));
+        let mut dockerfile = Dockerfile::new();
+        dockerfile.add(Command::FROM {
+            image: base_tag,
+            stage: None,
+        });
+        dockerfile.set_base(base);
+        assert!(dockerfile.tag().unwrap().starts_with("mini-cross2-"));
+        assert_ne!(
+            dockerfile.tag().unwrap(),
+            dockerfile.base().unwrap().tag().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_standalone() {
+        let mut base = Dockerfile::new_base();
+        base.add(Command::FROM {
+            image: "debian:trixie-slim".into(),
+            stage: None,
+        });
+        let mut dockerfile = Dockerfile::new();
+        dockerfile.add(Command::FROM {
+            image: base.tag().unwrap(),
+            stage: None,
+        });
+        dockerfile.set_base(base);
+        let mut buf = Vec::new();
+        dockerfile
+            .write_standalone_to(&mut BufWriter::new(&mut buf))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\n# Base image\nFROM debian:trixie-slim AS mc2-base\nFROM mc2-base\n"
+        );
+    }
+
+    #[test]
+    fn test_env_override() {
+        let mut dockerfile = Dockerfile::new();
+        dockerfile.add_env("A", "mixin");
+        dockerfile.add_env("B", "mixin");
+        dockerfile.add_env("C", "mixin");
+        dockerfile.add_env("A", "user");
+        dockerfile.remove_env("B");
+        dockerfile.add_env("A", "cli");
+        assert_eq!(
+            dockerfile.env,
+            Vec::from([("A".into(), "cli".into()), ("C".into(), "mixin".into())])
+        );
+    }
+}
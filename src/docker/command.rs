@@ -0,0 +1,506 @@
+use crate::docker::User;
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Starts the image or, if named, a build stage
+    FROM {
+        image: String,
+        stage: Option<String>,
+    },
+    COMMENT(String),
+    /// Default command, exec form
+    CMD(Vec<String>),
+    /// Exec form, arguments of `docker run` are appended
+    ENTRYPOINT(Vec<String>),
+    /// Shell used for the shell form of RUN, e.g. `["/bin/bash", "-c"]`
+    SHELL(Vec<String>),
+    ENV(String, String),
+    ARG(String, String),
+    LABEL(String, String),
+    /// Shell form, multi-line scripts are written as heredoc
+    RUN(String),
+    /// RUN with cache mounts kept between builds but not part of the image, needs BuildKit
+    CACHED {
+        caches: Vec<Cache>,
+        script: String,
+    },
+    USER(User),
+    WORKDIR(String),
+    /// Copies from the build context or, with `from`, out of a build stage
+    COPY {
+        from: Option<String>,
+        src: String,
+        dst: String,
+    },
+    /// Writes `content` to `path` in the image through a heredoc
+    FILE {
+        path: String,
+        content: String,
+        executable: bool,
+    },
+    EXPOSE(u16),
+    /// Test command in exec form, `None` disables the base image's healthcheck
+    HEALTHCHECK(Option<Vec<String>>),
+}
+
+/// BuildKit cache mount
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+    pub target: String,
+    /// Shares the cache between different targets, defaults to the target
+    pub id: Option<String>,
+    /// Owner of the cache directory, root by default
+    pub owner: Option<User>,
+}
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "--mount=type=cache,target={}", self.target)?;
+        if let Some(id) = &self.id {
+            write!(f, ",id={}", id)?;
+        }
+        if let Some(owner) = &self.owner {
+            write!(f, ",uid={}", owner.uid)?;
+            if let Some(gid) = owner.gid {
+                write!(f, ",gid={}", gid)?;
+            }
+        }
+        write!(f, ",sharing=locked")
+    }
+}
+
+impl Command {
+    pub fn env<A: ToString + ?Sized, B: ToString + ?Sized>(a: &A, b: &B) -> Self {
+        Self::ENV(a.to_string(), b.to_string())
+    }
+
+    pub fn arg<A: ToString + ?Sized, B: ToString + ?Sized>(a: &A, b: &B) -> Self {
+        Self::ARG(a.to_string(), b.to_string())
+    }
+
+    /// Checks the instruction can be written, values other than heredocs cannot span lines
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(ErrorKind::InvalidData, message));
+        let single_line = |value: &str| !value.contains(['\n', '\r']);
+        match self {
+            Command::ENV(key, value) | Command::ARG(key, value) => {
+                let valid_key = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+                if key.is_empty() || !key.chars().all(valid_key) {
+                    return invalid(format!("Invalid variable name in Dockerfile: '{}'", key));
+                }
+                if !single_line(value) {
+                    return invalid(format!("Value of {} cannot contain newlines", key));
+                }
+            }
+            Command::LABEL(key, value) if !single_line(key) || !single_line(value) => {
+                return invalid(format!("Label {} cannot contain newlines", key));
+            }
+            Command::FROM { image, stage } if !is_stage_name(stage.as_deref()) => {
+                return invalid(format!("Invalid stage name for {}: {:?}", image, stage));
+            }
+            Command::FROM { image: value, .. } | Command::WORKDIR(value) if !single_line(value) => {
+                return invalid(format!("'{}' cannot contain newlines", value));
+            }
+            Command::COPY { from, .. } if !is_stage_name(from.as_deref()) => {
+                return invalid(format!("Invalid stage name: {:?}", from));
+            }
+            Command::COPY { src, dst, .. } if !single_line(src) || !single_line(dst) => {
+                return invalid(format!(
+                    "Cannot copy {} to {}, paths contain newlines",
+                    src, dst
+                ));
+            }
+            Command::CACHED { caches, .. } => {
+                let valid = |x: &str| !x.is_empty() && !x.contains([',', '=', '"', ' ', '\n']);
+                for cache in caches {
+                    if !valid(&cache.target) || !cache.id.as_deref().is_none_or(valid) {
+                        return invalid(format!("Invalid cache directory: {}", cache.target));
+                    }
+                }
+            }
+            Command::FILE { path, .. } if !single_line(path) => {
+                return invalid(format!("File path '{}' cannot contain newlines", path));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::FROM { image, stage } => {
+                write!(f, "FROM {}", image)?;
+                match stage {
+                    Some(stage) => write!(f, " AS {}", stage),
+                    None => Ok(()),
+                }
+            }
+            Command::COMMENT(comment) => {
+                let lines = comment
+                    .lines()
+                    .map(|x| format!("# {}", x).trim_end().to_string());
+                write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+            }
+            Command::CMD(args) => write!(f, "CMD {}", exec_form(args)),
+            Command::ENTRYPOINT(args) => write!(f, "ENTRYPOINT {}", exec_form(args)),
+            Command::SHELL(args) => write!(f, "SHELL {}", exec_form(args)),
+            Command::ENV(key, value) => write!(f, "ENV {}={}", key, quote(value)),
+            Command::ARG(key, value) => write!(f, "ARG {}={}", key, quote(value)),
+            Command::LABEL(key, value) => write!(f, "LABEL {}={}", quote(key), quote(value)),
+            Command::RUN(script) if script.contains('\n') => {
+                write!(f, "RUN {}", heredoc(script))
+            }
+            Command::RUN(script) => write!(f, "RUN {}", script),
+            Command::CACHED { caches, script } => {
+                write!(f, "RUN ")?;
+                for cache in caches {
+                    write!(f, "{} ", cache)?;
+                }
+                match script.contains('\n') {
+                    true => write!(f, "{}", heredoc(script)),
+                    false => write!(f, "{}", script),
+                }
+            }
+            Command::USER(user) => write!(f, "USER {}", user),
+            Command::WORKDIR(path) => write!(f, "WORKDIR {}", quote(path)),
+            Command::COPY { from, src, dst } => {
+                write!(f, "COPY ")?;
+                if let Some(from) = from {
+                    write!(f, "--from={} ", from)?;
+                }
+                match is_plain(src) && is_plain(dst) {
+                    true => write!(f, "{} {}", src, dst),
+                    false => write!(f, "{}", exec_form(&[src.clone(), dst.clone()])),
+                }
+            }
+            Command::FILE {
+                path,
+                content,
+                executable,
+            } => {
+                write!(f, "COPY ")?;
+                if *executable {
+                    write!(f, "--chmod=755 ")?;
+                }
+                let (delimiter, body) = heredoc_parts(content);
+                write!(
+                    f,
+                    "<<\"{}\" {}\n{}{}",
+                    delimiter,
+                    quote(path),
+                    body,
+                    delimiter
+                )
+            }
+            Command::EXPOSE(port) => write!(f, "EXPOSE {}", port),
+            Command::HEALTHCHECK(Some(args)) => write!(f, "HEALTHCHECK CMD {}", exec_form(args)),
+            Command::HEALTHCHECK(None) => write!(f, "HEALTHCHECK NONE"),
+        }
+    }
+}
+
+/// Stage names are lowercase words, `None` is the unnamed final image
+fn is_stage_name(name: Option<&str>) -> bool {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c);
+    name.is_none_or(|x| x.starts_with(|c: char| c.is_ascii_lowercase()) && x.chars().all(valid))
+}
+
+/// JSON array, the form in which docker does not wrap the command into a shell
+fn exec_form(args: &[String]) -> String {
+    serde_json::to_string(args).expect("strings serialize to json")
+}
+
+fn is_plain(value: &str) -> bool {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_.,:/@%+".contains(c);
+    !value.is_empty() && value.chars().all(plain)
+}
+
+/// Double quotes `value` unless it is a plain word, `$` is escaped so it is not substituted
+fn quote(value: &str) -> String {
+    if is_plain(value) {
+        return value.to_string();
+    }
+    let mut result = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.push('"');
+    result
+}
+
+/// Delimiter not found as a line of `content` and `content` terminated by a newline.
+/// The delimiter is quoted when used, so `content` is not expanded.
+fn heredoc_parts(content: &str) -> (String, String) {
+    let mut delimiter = String::from("EOF");
+    let mut n = 0;
+    while content.lines().any(|x| x.trim() == delimiter) {
+        n += 1;
+        delimiter = format!("EOF_{}", n);
+    }
+    let mut body = content.to_string();
+    if !body.ends_with('\n') {
+        body.push('\n');
+    }
+    (delimiter, body)
+}
+
+fn heredoc(content: &str) -> String {
+    let (delimiter, body) = heredoc_parts(content);
+    format!("<<\"{}\"\n{}{}", delimiter, body, delimiter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a word at the start of `input`, plain or double quoted, until whitespace or `stop`
+    fn word(input: &str, stop: char) -> (String, &str) {
+        let mut result = String::new();
+        let mut chars = input.char_indices().peekable();
+        let mut quoted = false;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => result.push(chars.next().unwrap().1),
+                c if !quoted && (c == stop || c.is_whitespace()) => return (result, &input[i..]),
+                c => result.push(c),
+            }
+        }
+        (result, "")
+    }
+
+    fn key_value(input: &str) -> (String, String) {
+        let (key, rest) = word(input, '=');
+        let (value, _) = word(rest.strip_prefix('=').unwrap(), ' ');
+        (key, value)
+    }
+
+    fn json(input: &str) -> Vec<String> {
+        serde_json::from_str(input).unwrap()
+    }
+
+    /// Lines of a heredoc until its delimiter, each terminated by a newline
+    fn heredoc_body<'a>(header: &str, lines: &mut impl Iterator<Item = &'a str>) -> String {
+        let delimiter = header.split('"').nth(1).unwrap();
+        let mut body = String::new();
+        for line in lines.take_while(|x| *x != delimiter) {
+            body.push_str(line);
+            body.push('\n');
+        }
+        body
+    }
+
+    /// Minimal reader for what [`Command`] writes, to check values survive a round trip
+    fn parse(dockerfile: &str) -> Vec<Command> {
+        let mut result = Vec::new();
+        let mut lines = dockerfile.lines();
+        while let Some(line) = lines.next() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                result.push(Command::COMMENT(comment.into()));
+                continue;
+            }
+            let (instruction, rest) = line.split_once(' ').unwrap();
+            result.push(match instruction {
+                "FROM" => match rest.split_once(" AS ") {
+                    Some((image, stage)) => Command::FROM {
+                        image: image.into(),
+                        stage: Some(stage.into()),
+                    },
+                    None => Command::FROM {
+                        image: rest.into(),
+                        stage: None,
+                    },
+                },
+                "CMD" => Command::CMD(json(rest)),
+                "ENTRYPOINT" => Command::ENTRYPOINT(json(rest)),
+                "SHELL" => Command::SHELL(json(rest)),
+                "ENV" => {
+                    let (key, value) = key_value(rest);
+                    Command::ENV(key, value)
+                }
+                "ARG" => {
+                    let (key, value) = key_value(rest);
+                    Command::ARG(key, value)
+                }
+                "LABEL" => {
+                    let (key, value) = key_value(rest);
+                    Command::LABEL(key, value)
+                }
+                "RUN" if rest.starts_with("--mount=") => {
+                    let mut caches = Vec::new();
+                    let mut rest = rest;
+                    while let Some(mount) = rest.strip_prefix("--mount=") {
+                        let (mount, tail) = mount.split_once(' ').unwrap();
+                        let options = mount
+                            .split(',')
+                            .filter_map(|x| x.split_once('='))
+                            .collect::<Vec<_>>();
+                        let option = |key: &str| options.iter().find(|x| x.0 == key).map(|x| x.1);
+                        caches.push(Cache {
+                            target: option("target").unwrap().into(),
+                            id: option("id").map(String::from),
+                            owner: option("uid").map(|uid| User {
+                                uid: uid.parse().unwrap(),
+                                gid: option("gid").map(|x| x.parse().unwrap()),
+                            }),
+                        });
+                        rest = tail;
+                    }
+                    let script = match rest.starts_with("<<") {
+                        true => heredoc_body(rest, &mut lines).trim_end_matches('\n').into(),
+                        false => rest.into(),
+                    };
+                    Command::CACHED { caches, script }
+                }
+                "RUN" if rest.starts_with("<<") => {
+                    let body = heredoc_body(rest, &mut lines);
+                    Command::RUN(body.trim_end_matches('\n').into())
+                }
+                "RUN" => Command::RUN(rest.into()),
+                "USER" => {
+                    let (uid, gid) = rest.split_once(':').unwrap();
+                    Command::USER(User {
+                        uid: uid.parse().unwrap(),
+                        gid: Some(gid.parse().unwrap()),
+                    })
+                }
+                "WORKDIR" => Command::WORKDIR(word(rest, ' ').0),
+                "COPY" if rest.contains("<<") => {
+                    let executable = rest.starts_with("--chmod=755 ");
+                    let (header, path) = rest.rsplit_once("\" ").unwrap();
+                    Command::FILE {
+                        path: word(path, ' ').0,
+                        content: heredoc_body(header, &mut lines),
+                        executable,
+                    }
+                }
+                "COPY" => {
+                    let (from, rest) = match rest.strip_prefix("--from=") {
+                        Some(rest) => {
+                            let (from, rest) = rest.split_once(' ').unwrap();
+                            (Some(from.to_string()), rest)
+                        }
+                        None => (None, rest),
+                    };
+                    let (src, dst) = match rest.starts_with('[') {
+                        true => {
+                            let args = json(rest);
+                            (args[0].clone(), args[1].clone())
+                        }
+                        false => {
+                            let (src, dst) = rest.split_once(' ').unwrap();
+                            (src.to_string(), dst.to_string())
+                        }
+                    };
+                    Command::COPY { from, src, dst }
+                }
+                "EXPOSE" => Command::EXPOSE(rest.parse().unwrap()),
+                "HEALTHCHECK" => Command::HEALTHCHECK(rest.strip_prefix("CMD ").map(json)),
+                _ => panic!("unknown instruction {}", instruction),
+            });
+        }
+        result
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(
+            Command::env("LANGUAGE", "en_US:en").to_string(),
+            "ENV LANGUAGE=en_US:en"
+        );
+        assert_eq!(
+            Command::env("MY_VAR", "Hello \"$USER\"").to_string(),
+            r#"ENV MY_VAR="Hello \"\$USER\"""#
+        );
+        assert_eq!(
+            Command::RUN("a\nEOF\nb".into()).to_string(),
+            "RUN <<\"EOF_1\"\na\nEOF\nb\nEOF_1"
+        );
+        assert!(Command::env("A", "x\ny").validate().is_err());
+        assert!(Command::env("A B", "x").validate().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let commands = Vec::from([
+            Command::FROM {
+                image: "debian:trixie-slim".into(),
+                stage: Some("cmake".into()),
+            },
+            Command::FROM {
+                image: "debian:trixie-slim".into(),
+                stage: None,
+            },
+            Command::COMMENT("Exec script from: /p/mc.yaml".into()),
+            Command::env("MY_VAR", "Hello Word"),
+            Command::env("PRICE", "$5 \"each\" \\o/"),
+            Command::env("EMPTY", ""),
+            Command::arg("DEBIAN_FRONTEND", "noninteractive"),
+            Command::LABEL("org.example.title".into(), "a = b".into()),
+            Command::RUN("apt-get update && apt-get install -y gcc".into()),
+            Command::RUN("set -e\nmake\nmake install".into()),
+            Command::CACHED {
+                caches: Vec::from([
+                    Cache {
+                        target: "/var/cache/apt".into(),
+                        id: Some("debian:trixie/var/cache/apt".into()),
+                        owner: None,
+                    },
+                    Cache {
+                        target: "/home/user/.cargo/registry".into(),
+                        id: None,
+                        owner: Some(User {
+                            uid: 1000,
+                            gid: Some(1000),
+                        }),
+                    },
+                ]),
+                script: "apt install -y gcc".into(),
+            },
+            Command::USER(User {
+                uid: 1000,
+                gid: Some(1000),
+            }),
+            Command::WORKDIR("/opt/my project".into()),
+            Command::COPY {
+                from: None,
+                src: "/p/a file".into(),
+                dst: "/a file".into(),
+            },
+            Command::COPY {
+                from: Some("cmake".into()),
+                src: "/opt/cmake".into(),
+                dst: "/opt/cmake".into(),
+            },
+            Command::FILE {
+                path: "/usr/local/share/mc2/script-0".into(),
+                content: "#!/bin/sh\ncat <<EOF\nEOF\nEOF_1\n".into(),
+                executable: true,
+            },
+            Command::EXPOSE(80),
+            Command::HEALTHCHECK(None),
+            Command::HEALTHCHECK(Some(Vec::from(["pg_isready".into()]))),
+            Command::SHELL(Vec::from(["/bin/bash".into(), "-c".into()])),
+            Command::ENTRYPOINT(Vec::from([
+                "/bin/sh".into(),
+                "-c".into(),
+                "echo \"$@\"".into(),
+            ])),
+            Command::CMD(Vec::from(["/usr/bin/env".into(), "bash".into()])),
+        ]);
+        let dockerfile = commands
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(parse(&dockerfile), commands);
+    }
+}
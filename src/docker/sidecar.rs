@@ -0,0 +1,240 @@
+use crate::config::{Publish, Volume};
+use crate::signal;
+use sha2::Digest;
+use std::io::{self, ErrorKind};
+use std::process::{self, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
+/// Time a sidecar gets to run and, if its image has a healthcheck, to become healthy
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Image a sidecar runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SidecarImage {
+    Image(String),
+    /// mc2 machine, resolved to the tag of its image before the run
+    Machine(String),
+}
+
+/// Container started next to the machine, reachable by its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub name: String,
+    pub image: SidecarImage,
+    /// Command of the image if empty
+    pub command: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub publish: Vec<Publish>,
+    pub volumes: Vec<Volume>,
+}
+
+impl Sidecar {
+    fn args(&self, container: &str, network: &str) -> io::Result<Vec<String>> {
+        let SidecarImage::Image(image) = &self.image else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Machine of service {} has not been built", self.name),
+            ));
+        };
+        let mut args = Vec::from([
+            "run".into(),
+            "--detach".into(),
+            "--name".into(),
+            container.into(),
+            "--network".into(),
+            network.into(),
+            "--network-alias".into(),
+            self.name.clone(),
+        ]);
+        args.extend(
+            self.publish
+                .iter()
+                .flat_map(|x| ["-p".into(), x.to_string()]),
+        );
+        args.extend(
+            self.volumes
+                .iter()
+                .flat_map(|x| ["-v".into(), x.to_string()]),
+        );
+        args.extend(
+            self.env
+                .iter()
+                .flat_map(|(k, v)| ["-e".into(), format!("{}={}", k, v)]),
+        );
+        args.push(image.clone());
+        args.extend(self.command.iter().cloned());
+        Ok(args)
+    }
+}
+
+/// Running sidecars of one run, they and the network are removed on drop
+#[derive(Debug)]
+pub struct Sidecars {
+    network: String,
+    created_network: bool,
+    /// `(service name, container name)`
+    containers: Vec<(String, String)>,
+}
+
+impl Sidecars {
+    /// Starts the sidecars on the network of the project and waits until they are ready,
+    /// containers are named `<prefix>-<name>`
+    pub fn start(sidecars: &[Sidecar], prefix: &str) -> io::Result<Self> {
+        let network = project_network()?;
+        let mut result = Sidecars {
+            created_network: false,
+            network,
+            containers: Vec::new(),
+        };
+        if !docker_quiet(&["network", "inspect", &result.network])? {
+            if !docker_quiet(&["network", "create", &result.network])? {
+                return Err(io::Error::other(format!(
+                    "Failed to create network {}",
+                    result.network
+                )));
+            }
+            result.created_network = true;
+        }
+        for sidecar in sidecars {
+            let container = format!("{}-{}", prefix, sidecar.name);
+            let status = process::Command::new("docker")
+                .args(sidecar.args(&container, &result.network)?)
+                .stdout(Stdio::null())
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "Failed to start service {}",
+                    sidecar.name
+                )));
+            }
+            result.containers.push((sidecar.name.clone(), container));
+        }
+        for (name, container) in &result.containers {
+            wait_ready(name, container)?;
+        }
+        Ok(result)
+    }
+
+    /// Network the machine joins to reach the sidecars
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    /// Addresses of the services on the network by name, for checks run on the host
+    pub fn addresses(&self) -> io::Result<Vec<(String, String)>> {
+        let format = format!(
+            "{{{{with index .NetworkSettings.Networks \"{}\"}}}}{{{{.IPAddress}}}}{{{{end}}}}",
+            self.network
+        );
+        let mut result = Vec::new();
+        for (name, container) in &self.containers {
+            let output = process::Command::new("docker")
+                .args(["inspect", "--format", &format, container])
+                .output()?;
+            let address = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if output.status.success() && !address.is_empty() {
+                result.push((name.clone(), address));
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for Sidecars {
+    fn drop(&mut self) {
+        for (_, container) in self.containers.iter().rev() {
+            let _ = docker_quiet(&["rm", "--force", container]);
+        }
+        // Fails while another run of the project still uses it
+        if self.created_network {
+            let _ = docker_quiet(&["network", "rm", &self.network]);
+        }
+    }
+}
+
+/// Network shared by the runs in the current directory
+fn project_network() -> io::Result<String> {
+    let project = env::current_dir()?;
+    let hash = sha2::Sha256::digest(project.as_os_str().as_encoded_bytes());
+    Ok(format!("mc2-{}", &hex::encode(hash)[..12]))
+}
+
+/// Runs docker without output, returns whether it succeeded
+fn docker_quiet(args: &[&str]) -> io::Result<bool> {
+    Ok(process::Command::new("docker")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success())
+}
+
+/// Waits until the container runs and is healthy, shows its logs if it exits
+fn wait_ready(name: &str, container: &str) -> io::Result<()> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        let output = process::Command::new("docker")
+            .args([
+                "inspect",
+                "--format",
+                "{{.State.Status}} {{if .State.Health}}{{.State.Health.Status}}{{end}}",
+                container,
+            ])
+            .output()?;
+        let state = String::from_utf8_lossy(&output.stdout);
+        let mut state = state.split_whitespace();
+        match (state.next(), state.next()) {
+            (Some("running"), None | Some("healthy")) => return Ok(()),
+            (Some("exited" | "dead"), _) => {
+                let _ = process::Command::new("docker")
+                    .args(["logs", "--tail", "20", container])
+                    .status();
+                return Err(io::Error::other(format!("Service {} exited", name)));
+            }
+            _ => {}
+        }
+        if signal::take().is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Interrupted,
+                format!("Interrupted waiting for service {}", name),
+            ));
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "Service {} is not ready after {}s",
+                    name,
+                    START_TIMEOUT.as_secs()
+                ),
+            ));
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_args() {
+        let mut sidecar = Sidecar {
+            name: "db".into(),
+            image: SidecarImage::Machine("postgres".into()),
+            command: Vec::from(["postgres".into(), "-N".into(), "10".into()]),
+            env: Vec::from([("POSTGRES_PASSWORD".into(), "dev".into())]),
+            publish: Vec::from([Publish::from_str("5432:5432").unwrap()]),
+            volumes: Vec::new(),
+        };
+        assert!(sidecar.args("mc2-x-db", "mc2-net").is_err());
+        sidecar.image = SidecarImage::Image("postgres:16".into());
+        assert_eq!(
+            sidecar.args("mc2-x-db", "mc2-net").unwrap().join(" "),
+            "run --detach --name mc2-x-db --network mc2-net --network-alias db \
+             -p 5432:5432 -e POSTGRES_PASSWORD=dev postgres:16 postgres -N 10"
+        );
+    }
+}
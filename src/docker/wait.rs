@@ -0,0 +1,276 @@
+use crate::signal;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a single connection attempt may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What has to be ready before the command runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// `<host>:<port>` accepting connections
+    Tcp(String),
+    /// `http://` URL answering with status 200
+    Http(String),
+    File(PathBuf),
+    /// Shell command exiting successfully
+    Command(String),
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Tcp(address) => write!(f, "tcp {}", address),
+            Condition::Http(url) => write!(f, "http {}", url),
+            Condition::File(path) => write!(f, "file {}", path.display()),
+            Condition::Command(command) => write!(f, "command {}", command),
+        }
+    }
+}
+
+impl Condition {
+    /// Checks the condition once within the deadline, the error tells why it is not
+    /// ready. Hosts named in `hosts` connect to the address given there.
+    fn check(&self, deadline: Instant, hosts: &[(String, String)]) -> Result<(), String> {
+        match self {
+            Condition::Tcp(address) => connect(&resolve(address, hosts), deadline).map(|_| ()),
+            Condition::Http(url) => {
+                let (address, path) = http_target(url).ok_or("invalid URL")?;
+                let mut stream = connect(&resolve(address, hosts), deadline)?;
+                let host = address.rsplit_once(':').map_or(address, |x| x.0);
+                let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host);
+                // Only the status line matters, the body may be anything and never end
+                let mut status = Vec::new();
+                stream
+                    .set_read_timeout(Some(remaining(deadline)?))
+                    .and_then(|_| stream.write_all(request.as_bytes()))
+                    .and_then(|_| {
+                        BufReader::new(stream)
+                            .take(1024)
+                            .read_until(b'\n', &mut status)
+                    })
+                    .map_err(|e| e.to_string())?;
+                let status = String::from_utf8_lossy(&status);
+                let status = status.trim_end();
+                match status.split_whitespace().nth(1) {
+                    Some("200") => Ok(()),
+                    _ => Err(format!("answered {}", status)),
+                }
+            }
+            Condition::File(path) => match path.exists() {
+                true => Ok(()),
+                false => Err("does not exist".into()),
+            },
+            Condition::Command(command) => run(command, deadline),
+        }
+    }
+}
+
+/// Condition with the time it gets to become ready
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wait {
+    pub condition: Condition,
+    pub timeout: Duration,
+}
+
+/// Waits for the conditions in order, fails with the reason of the first one timing out
+pub fn wait_for(waits: &[Wait], hosts: &[(String, String)]) -> io::Result<()> {
+    for wait in waits {
+        let deadline = Instant::now() + wait.timeout;
+        let mut announced = false;
+        while let Err(reason) = wait.condition.check(deadline, hosts) {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "Timed out after {}s waiting for {}: {}",
+                        wait.timeout.as_secs(),
+                        wait.condition,
+                        reason
+                    ),
+                ));
+            }
+            if signal::take().is_some() {
+                return Err(io::Error::new(
+                    ErrorKind::Interrupted,
+                    format!("Interrupted waiting for {}", wait.condition),
+                ));
+            }
+            if !announced {
+                eprintln!("Waiting for {}...", wait.condition);
+                announced = true;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+    Ok(())
+}
+
+/// Address and path of an `http://` URL, the port defaults to 80
+pub fn http_target(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (address, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    (!address.is_empty()).then_some((address, path))
+}
+
+/// Replaces the host of `<host>[:<port>]` by its address in `hosts`
+fn resolve(address: &str, hosts: &[(String, String)]) -> String {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (address, None),
+    };
+    let host = hosts
+        .iter()
+        .find_map(|(name, ip)| (name == host).then_some(ip.as_str()))
+        .unwrap_or(host);
+    match port {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Time left until the deadline, an error once it passed
+fn remaining(deadline: Instant) -> Result<Duration, String> {
+    match deadline.saturating_duration_since(Instant::now()) {
+        Duration::ZERO => Err("timed out".into()),
+        remaining => Ok(remaining),
+    }
+}
+
+fn connect(address: &str, deadline: Instant) -> Result<TcpStream, String> {
+    let with_port;
+    let address = match address.contains(':') {
+        true => address,
+        false => {
+            with_port = format!("{}:80", address);
+            &with_port
+        }
+    };
+    let mut last = String::from("no address");
+    for address in address.to_socket_addrs().map_err(|e| e.to_string())? {
+        let timeout = remaining(deadline)?.min(CONNECT_TIMEOUT);
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e.to_string(),
+        }
+    }
+    Err(last)
+}
+
+/// Runs a shell command in its own process group, which is killed when the command
+/// exits or the deadline passes
+fn run(command: &str, deadline: Instant) -> Result<(), String> {
+    let mut child = process::Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let mut stderr = child.stderr.take();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(stderr) = &mut stderr {
+            let _ = stderr.read_to_end(&mut output);
+        }
+        output
+    });
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Ok(None) => break Err("timed out".to_string()),
+            Err(e) => break Err(e.to_string()),
+        }
+    };
+    // Also ends what the command left running in the background
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    let _ = child.wait();
+    let status = status?;
+    let stderr = reader.join().unwrap_or_default();
+    match status.success() {
+        true => Ok(()),
+        false => {
+            let stderr = String::from_utf8_lossy(&stderr);
+            let line = stderr.lines().last().unwrap_or_default();
+            Err(format!("{}: {}", status, line))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_http_target() {
+        assert_eq!(
+            http_target("http://localhost:8080/health"),
+            Some(("localhost:8080", "/health"))
+        );
+        assert_eq!(http_target("http://db"), Some(("db", "/")));
+        assert_eq!(http_target("https://db"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 256];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.0 503 Unavailable\r\n\r\n\xff\xfe")
+                .unwrap();
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        // Reached by the name of a service
+        let hosts = [("web".to_string(), "127.0.0.1".to_string())];
+        let port = address.rsplit_once(':').unwrap().1;
+        let http = Condition::Http(format!("http://web:{}/health", port));
+        assert_eq!(
+            http.check(deadline, &hosts),
+            Err("answered HTTP/1.0 503 Unavailable".into())
+        );
+        server.join().unwrap();
+
+        assert!(
+            Condition::Command("true".into())
+                .check(deadline, &[])
+                .is_ok()
+        );
+        assert!(
+            Condition::Command("exit 3".into())
+                .check(deadline, &[])
+                .is_err()
+        );
+        let start = Instant::now();
+        let sleep = Condition::Command("sleep 10".into());
+        let soon = start + Duration::from_millis(200);
+        assert_eq!(sleep.check(soon, &[]), Err("timed out".into()));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(Condition::File("/".into()).check(deadline, &[]).is_ok());
+        let wait = Wait {
+            condition: Condition::File("/does/not/exist".into()),
+            timeout: Duration::ZERO,
+        };
+        let error = wait_for(&[wait], &[]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            error.to_string(),
+            "Timed out after 0s waiting for file /does/not/exist: does not exist"
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use crate::config::{EnvEntry, Mixin, Publish, UserConfig, normalized_path, resolve_env};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Effective value of a setting, with the file it came from, the definitions it shadowed
+/// and the ones applied next to it that clash with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub key: String,
+    /// `None` if the setting was unset
+    pub value: Option<String>,
+    pub source: PathBuf,
+    pub shadowed: Vec<(Option<String>, PathBuf)>,
+    pub conflicts: Vec<(Option<String>, PathBuf)>,
+}
+
+/// Settings keyed by name, in order of first definition
+#[derive(Debug, Default)]
+pub struct Section {
+    pub settings: Vec<Setting>,
+}
+
+impl Section {
+    /// Defines `key`, the last definition wins
+    fn define(&mut self, key: String, value: Option<String>, source: &Path) {
+        match self.settings.iter_mut().find(|x| x.key == key) {
+            Some(setting) => {
+                let old_value = std::mem::replace(&mut setting.value, value);
+                let old_source = std::mem::replace(&mut setting.source, source.to_path_buf());
+                setting.shadowed.push((old_value, old_source));
+            }
+            None => self.settings.push(Setting {
+                key,
+                value,
+                source: source.to_path_buf(),
+                shadowed: Vec::new(),
+                conflicts: Vec::new(),
+            }),
+        }
+    }
+
+    /// Defines `key` where every definition is applied, so a second one conflicts
+    fn define_all(&mut self, key: String, value: Option<String>, source: &Path) {
+        let same_key = key.clone();
+        self.define_all_by(key, value, source, |x| x.key == same_key)
+    }
+
+    /// Like [`Self::define_all`], the definition conflicts with the first setting `clashes`
+    /// matches
+    fn define_all_by(
+        &mut self,
+        key: String,
+        value: Option<String>,
+        source: &Path,
+        clashes: impl Fn(&Setting) -> bool,
+    ) {
+        match self.settings.iter_mut().find(|x| clashes(x)) {
+            Some(setting) => setting.conflicts.push((value, source.to_path_buf())),
+            None => self.settings.push(Setting {
+                key,
+                value,
+                source: source.to_path_buf(),
+                shadowed: Vec::new(),
+                conflicts: Vec::new(),
+            }),
+        }
+    }
+
+    /// Defines `key`, the first definition wins
+    fn define_once(&mut self, key: String, value: Option<String>, source: &Path) {
+        match self.settings.iter_mut().find(|x| x.key == key) {
+            Some(setting) => setting.shadowed.push((value, source.to_path_buf())),
+            None => self.settings.push(Setting {
+                key,
+                value,
+                source: source.to_path_buf(),
+                shadowed: Vec::new(),
+                conflicts: Vec::new(),
+            }),
+        }
+    }
+}
+
+/// Include tree of a toolchain and where each effective setting was defined
+#[derive(Debug, Default)]
+pub struct Explanation {
+    pub tree: Vec<(usize, PathBuf)>,
+    pub base: Section,
+    pub packages: Section,
+    pub env: Section,
+    pub volumes: Section,
+    pub ports: Section,
+}
+
+impl Explanation {
+    pub fn new(root: &Mixin, user_configs: &[(PathBuf, UserConfig)]) -> io::Result<Self> {
+        let mut explanation = Explanation::default();
+        let mut visited = Vec::new();
+        explanation.walk(root, root, 0, &mut visited);
+
+        // Same order the conversion flattens mixins in
+        let mut mixins: Vec<&Mixin> = Vec::from_iter(&root.children);
+        mixins.push(root);
+        for mixin in mixins {
+            let yaml = &mixin.yaml;
+            if let Some(base) = &yaml.base {
+                explanation
+                    .base
+                    .define_once("base".into(), Some(base.clone()), &mixin.path);
+            }
+            for package in yaml.install.iter().flatten() {
+                explanation.packages.define_once(
+                    package.clone(),
+                    Some(package.clone()),
+                    &mixin.path,
+                );
+            }
+            explanation.define_env(mixin.resolve_env()?, &mixin.path);
+            for volume in yaml.volume.iter().flatten() {
+                let mut volume = volume.clone();
+                volume.host_path = mixin.add_parent_path(&volume.host_path);
+                explanation.volumes.define_all(
+                    volume.machine_path.display().to_string(),
+                    Some(volume.to_string()),
+                    &mixin.path,
+                );
+            }
+            for publish in yaml.publish.iter().flatten() {
+                explanation.define_port(publish, &mixin.path);
+            }
+        }
+
+        for (path, config) in user_configs {
+            explanation.define_env(
+                resolve_env(
+                    config.env_file.iter().flatten(),
+                    config.passthrough.iter().flatten(),
+                    config.env.as_ref(),
+                )?,
+                path,
+            );
+            for volume in config.volume.iter().flatten() {
+                explanation.volumes.define_all(
+                    volume.machine_path.display().to_string(),
+                    Some(volume.to_string()),
+                    path,
+                );
+            }
+            for publish in config.publish.iter().flatten() {
+                explanation.define_port(publish, path);
+            }
+        }
+        Ok(explanation)
+    }
+
+    /// Ports conflict like `mc2 check` reports them, a port without IP binds every address
+    fn define_port(&mut self, publish: &Publish, path: &Path) {
+        self.ports.define_all_by(
+            host_port(publish),
+            Some(publish.to_string()),
+            path,
+            |setting| {
+                let other = setting.value.as_deref().map(Publish::from_str);
+                other.is_some_and(|x| x.is_ok_and(|x| x.conflicts(publish)))
+            },
+        );
+    }
+
+    fn define_env(&mut self, env: Vec<EnvEntry>, path: &Path) {
+        for entry in env {
+            let source = entry.file.as_deref().unwrap_or(path);
+            self.env.define(entry.key, entry.value, source);
+        }
+    }
+
+    fn walk(&mut self, root: &Mixin, mixin: &Mixin, depth: usize, visited: &mut Vec<PathBuf>) {
+        self.tree.push((depth, mixin.path.clone()));
+        if visited.contains(&mixin.path) {
+            return;
+        }
+        visited.push(mixin.path.clone());
+        for include in mixin.yaml.mixin.iter().flatten() {
+            let path = normalized_path(mixin, include);
+            if let Some(child) = root.children.iter().find(|x| x.path == path) {
+                self.walk(root, child, depth + 1, visited);
+            }
+        }
+    }
+}
+
+fn host_port(publish: &Publish) -> String {
+    match &publish.host_ip {
+        Some(ip) => format!("{}:{}", ip, publish.host_port),
+        None => publish.host_port.to_string(),
+    }
+}
+
+fn write_section(
+    f: &mut Formatter<'_>,
+    title: &str,
+    section: &Section,
+    shadow: &str,
+    label: impl Fn(&str, Option<&str>) -> String,
+) -> std::fmt::Result {
+    writeln!(f, "\n{}:", title)?;
+    if section.settings.is_empty() {
+        writeln!(f, "  (none)")?;
+    }
+    for setting in &section.settings {
+        writeln!(
+            f,
+            "  {}  ({})",
+            label(&setting.key, setting.value.as_deref()),
+            setting.source.display()
+        )?;
+        for (value, source) in &setting.shadowed {
+            writeln!(
+                f,
+                "      {} {}  ({})",
+                shadow,
+                label(&setting.key, value.as_deref()),
+                source.display()
+            )?;
+        }
+        for (value, source) in &setting.conflicts {
+            writeln!(
+                f,
+                "      conflicts with {}  ({})",
+                label(&setting.key, value.as_deref()),
+                source.display()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Include tree:")?;
+        let mut seen = Vec::new();
+        for (depth, path) in &self.tree {
+            write!(f, "{}- {}", "  ".repeat(*depth + 1), path.display())?;
+            if seen.contains(&path) {
+                write!(f, " (already included)")?;
+            }
+            writeln!(f)?;
+            seen.push(path);
+        }
+        let value = |_: &str, v: Option<&str>| v.unwrap_or_default().to_string();
+        write_section(f, "Base", &self.base, "ignores", value)?;
+        write_section(f, "Packages", &self.packages, "ignores duplicate", value)?;
+        write_section(f, "Environment", &self.env, "shadows", |k, v| match v {
+            Some(v) => format!("{}={}", k, v),
+            None => format!("{} (unset)", k),
+        })?;
+        // Volumes and ports are all applied, they have no shadowed definitions
+        write_section(f, "Volumes", &self.volumes, "shadows", value)?;
+        write_section(f, "Ports", &self.ports, "shadows", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufReader, Cursor};
+
+    fn mixin(path: &str, content: &str) -> Mixin {
+        let reader = BufReader::new(Cursor::new(content.as_bytes().to_vec()));
+        Mixin::try_from((Path::new(path), reader)).unwrap()
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut root = mixin(
+            "/p/mc.yaml",
+            "---\nmixin:\n  - base\ninstall:\n  - gcc\n  - make\nenv:\n  A: root\npublish:\n  - 8080:80\n  - 9090:90\n---\n",
+        );
+        root.children.push(mixin(
+            "/p/base.yaml",
+            "---\nbase: ubuntu:22.04\ninstall:\n  - gcc\nenv:\n  A: base\n  B: base\n---\n",
+        ));
+        let user = UserConfig {
+            publish: Some(Vec::from([
+                "127.0.0.1:8080:81".parse().unwrap(),
+                "127.0.0.1:9091:91".parse().unwrap(),
+            ])),
+            volume: None,
+            env: Some(HashMap::from([("B".into(), None)])),
+            env_file: None,
+            passthrough: None,
+            ssh_agent: None,
+            ssh_known_hosts: None,
+            forward: None,
+            gui: None,
+            docker_socket: None,
+        };
+        let explanation =
+            Explanation::new(&root, &[("/home/u/.mc2config.yaml".into(), user)]).unwrap();
+
+        assert_eq!(
+            explanation.tree,
+            Vec::from([(0, "/p/mc.yaml".into()), (1, "/p/base.yaml".into())])
+        );
+        assert_eq!(
+            explanation.base.settings[0].source,
+            Path::new("/p/base.yaml")
+        );
+        let gcc = &explanation.packages.settings[0];
+        assert_eq!(gcc.source, Path::new("/p/base.yaml"));
+        assert_eq!(
+            gcc.shadowed,
+            Vec::from([(Some("gcc".into()), "/p/mc.yaml".into())])
+        );
+        let a = explanation
+            .env
+            .settings
+            .iter()
+            .find(|x| x.key == "A")
+            .unwrap();
+        assert_eq!(a.value.as_deref(), Some("root"));
+        assert_eq!(
+            a.shadowed,
+            Vec::from([(Some("base".into()), "/p/base.yaml".into())])
+        );
+        let b = explanation
+            .env
+            .settings
+            .iter()
+            .find(|x| x.key == "B")
+            .unwrap();
+        assert_eq!(b.value, None);
+        assert_eq!(b.source, Path::new("/home/u/.mc2config.yaml"));
+        let port = &explanation.ports.settings[0];
+        assert_eq!(port.value.as_deref(), Some("8080:80"));
+        assert_eq!(port.source, Path::new("/p/mc.yaml"));
+        assert!(port.shadowed.is_empty());
+        // A bare port binds every address, so it clashes with one bound to an IP
+        assert_eq!(
+            port.conflicts,
+            Vec::from([(
+                Some("127.0.0.1:8080:81".into()),
+                "/home/u/.mc2config.yaml".into()
+            )])
+        );
+        assert!(
+            explanation
+                .to_string()
+                .contains("      conflicts with 127.0.0.1:8080:81  (/home/u/.mc2config.yaml)")
+        );
+        assert!(explanation.ports.settings[1].conflicts.is_empty());
+        assert_eq!(explanation.ports.settings.len(), 3);
+    }
+}
@@ -0,0 +1,571 @@
+use crate::config::Volume;
+use crate::docker::{Dockerfile, EnvOrigin};
+use crate::forward;
+use clap::ValueEnum;
+use serde_json::{Map, Value, json};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::{env, fs};
+
+/// Formats a machine can be exported to
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// `.devcontainer/Dockerfile` and `.devcontainer/devcontainer.json` for VS Code
+    Devcontainer,
+    /// Compose file running the built image as service
+    Compose,
+    /// Kubernetes Deployment running the built image
+    K8s,
+}
+
+pub struct Export {
+    pub format: Format,
+    /// Name of the machine, used as name of the environment
+    pub machine: Option<String>,
+    /// Directory written to for devcontainer, file written to instead of stdout otherwise
+    pub output: Option<PathBuf>,
+}
+
+impl Export {
+    pub fn run(&self, dockerfile: &Dockerfile) -> io::Result<()> {
+        match self.format {
+            Format::Devcontainer => {
+                let dir = self
+                    .output
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(".devcontainer"));
+                let project = env::current_dir()?;
+                let context = build_context(&dir, &project).ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "{} is outside of the project {}, the build context would not \
+                             reach it",
+                            dir.display(),
+                            project.display()
+                        ),
+                    )
+                })?;
+                fs::create_dir_all(&dir)?;
+
+                let path = dir.join("Dockerfile");
+                let mut writer = BufWriter::new(fs::File::create(&path)?);
+                dockerfile.write_standalone_to(&mut writer)?;
+                writer.flush()?;
+                println!("Created {}", path.display());
+
+                let path = dir.join("devcontainer.json");
+                let json = devcontainer(
+                    dockerfile,
+                    self.machine.as_deref().unwrap_or("mc"),
+                    &project,
+                    &context,
+                );
+                let mut content = serde_json::to_string_pretty(&json)?;
+                content.push('\n');
+                fs::write(&path, content)?;
+                println!("Created {}", path.display());
+            }
+            Format::Compose | Format::K8s => {
+                if !dockerfile.exists()? {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "Image {} does not exist, build it by running the machine \
+                             with the same options first",
+                            dockerfile.tag()?
+                        ),
+                    ));
+                }
+                let service = Service::new(dockerfile, self.machine.as_deref())?;
+                let yaml = match self.format {
+                    Format::Compose => compose(&service),
+                    _ => k8s(&service),
+                };
+                let content = serde_yaml::to_string(&yaml)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                match &self.output {
+                    Some(path) => {
+                        fs::write(path, content)?;
+                        println!("Created {}", path.display());
+                    }
+                    None => print!("{}", content),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What compose and Kubernetes need to run the image like `mc2` does
+struct Service {
+    /// Name of the service, the machine
+    name: String,
+    image: String,
+    /// Project directory, mounted at the same path
+    project: PathBuf,
+    /// Bind mounts with absolute host paths, `(host, container, readonly)`
+    mounts: Vec<(String, String, bool)>,
+    /// `(host ip, host port, container port)`
+    ports: Vec<(Option<String>, u16, u16)>,
+    /// Literal variables
+    env: Vec<(String, String)>,
+    /// Variables passed through from the host, their values are not written
+    host_env: Vec<String>,
+    /// Env files variables are read from, absolute host paths
+    env_files: Vec<String>,
+    uid: u32,
+    gid: u32,
+    /// Supplementary groups of the user
+    groups: Vec<u32>,
+    init: bool,
+}
+
+impl Service {
+    fn new(dockerfile: &Dockerfile, machine: Option<&str>) -> io::Result<Self> {
+        let project = env::current_dir()?;
+        let mut mounts = Vec::from([(
+            project.display().to_string(),
+            project.display().to_string(),
+            false,
+        )]);
+        for volume in dockerfile.volumes() {
+            let readonly = volume.opts.iter().any(|x| x == "ro" || x == "readonly");
+            mounts.push((
+                project
+                    .join(
+                        volume
+                            .host_path
+                            .strip_prefix("./")
+                            .unwrap_or(&volume.host_path),
+                    )
+                    .display()
+                    .to_string(),
+                volume.machine_path.display().to_string(),
+                readonly,
+            ));
+        }
+        let mut groups = Vec::new();
+        if dockerfile.docker_socket() {
+            let socket =
+                forward::docker_socket().unwrap_or_else(|| PathBuf::from(forward::DOCKER_SOCK));
+            // The user gets the socket's group like on docker run, never the root group
+            groups.extend(socket.metadata().map(|x| x.gid()).ok().filter(|x| *x != 0));
+            mounts.push((
+                socket.display().to_string(),
+                forward::DOCKER_SOCK.to_string(),
+                false,
+            ));
+        }
+        let mut env = Vec::new();
+        let mut host_env = Vec::new();
+        let mut env_files = Vec::new();
+        for (k, v) in dockerfile.env() {
+            match dockerfile.env_origin(k) {
+                None => env.push((k.clone(), v.clone())),
+                Some(EnvOrigin::Host) => host_env.push(k.clone()),
+                Some(EnvOrigin::File(path)) => {
+                    let path = project.join(path).display().to_string();
+                    if !env_files.contains(&path) {
+                        env_files.push(path);
+                    }
+                }
+            }
+        }
+        Ok(Service {
+            name: machine.filter(|x| *x != "_").unwrap_or("mc").to_string(),
+            image: dockerfile.tag()?,
+            project,
+            mounts,
+            ports: dockerfile
+                .publishes()
+                .iter()
+                .map(|x| (x.host_ip.clone(), x.host_port, x.machine_port))
+                .collect(),
+            env,
+            host_env,
+            env_files,
+            uid: users::get_current_uid(),
+            gid: users::get_current_gid(),
+            groups,
+            init: dockerfile.init(),
+        })
+    }
+
+    /// Name usable as Kubernetes object name
+    fn dns_name(&self) -> String {
+        let name = self
+            .name
+            .to_lowercase()
+            .replace(|x: char| !x.is_ascii_alphanumeric(), "-");
+        name.trim_matches('-').to_string()
+    }
+}
+
+/// Compose file with the machine as only service, a terminal keeps bash running
+fn compose(service: &Service) -> Value {
+    let mut spec = Map::new();
+    spec.insert("image".into(), service.image.clone().into());
+    spec.insert(
+        "user".into(),
+        format!("{}:{}", service.uid, service.gid).into(),
+    );
+    if !service.groups.is_empty() {
+        spec.insert("group_add".into(), Value::from(service.groups.clone()));
+    }
+    spec.insert(
+        "working_dir".into(),
+        service.project.display().to_string().into(),
+    );
+    let volumes = service
+        .mounts
+        .iter()
+        .map(|(host, container, readonly)| match readonly {
+            true => format!("{}:{}:ro", host, container),
+            false => format!("{}:{}", host, container),
+        });
+    spec.insert("volumes".into(), Value::from_iter(volumes));
+    if !service.ports.is_empty() {
+        let ports = service.ports.iter().map(|(ip, host, container)| match ip {
+            Some(ip) => format!("{}:{}:{}", ip, host, container),
+            None => format!("{}:{}", host, container),
+        });
+        spec.insert("ports".into(), Value::from_iter(ports));
+    }
+    if !service.env_files.is_empty() {
+        spec.insert("env_file".into(), Value::from(service.env_files.clone()));
+    }
+    // Compose substitutes the host variables when it starts the service
+    let env = service
+        .env
+        .iter()
+        .map(|(k, v)| (k.clone(), Value::from(v.replace('$', "$$"))))
+        .chain(
+            service
+                .host_env
+                .iter()
+                .map(|k| (k.clone(), Value::from(format!("${{{}}}", k)))),
+        )
+        .collect::<Map<_, _>>();
+    if !env.is_empty() {
+        spec.insert("environment".into(), Value::Object(env));
+    }
+    if service.init {
+        spec.insert("init".into(), true.into());
+    }
+    spec.insert("stdin_open".into(), true.into());
+    spec.insert("tty".into(), true.into());
+    json!({ "services": { service.name.clone(): spec } })
+}
+
+/// Deployment of one pod running the machine on the node's image, mounts are host paths
+fn k8s(service: &Service) -> Value {
+    let name = service.dns_name();
+    let mut container = Map::new();
+    container.insert("name".into(), name.clone().into());
+    container.insert("image".into(), service.image.clone().into());
+    // The image only exists locally
+    container.insert("imagePullPolicy".into(), "Never".into());
+    container.insert(
+        "workingDir".into(),
+        service.project.display().to_string().into(),
+    );
+    container.insert("stdin".into(), true.into());
+    container.insert("tty".into(), true.into());
+    container.insert(
+        "securityContext".into(),
+        json!({ "runAsUser": service.uid, "runAsGroup": service.gid }),
+    );
+    if !service.ports.is_empty() {
+        let ports = service.ports.iter().map(|(ip, host, container)| {
+            let mut port = json!({ "containerPort": container, "hostPort": host });
+            if let Some(ip) = ip {
+                port["hostIP"] = ip.clone().into();
+            }
+            port
+        });
+        container.insert("ports".into(), Value::from_iter(ports));
+    }
+    if !service.env.is_empty() {
+        let env = service
+            .env
+            .iter()
+            .map(|(k, v)| json!({ "name": k, "value": v }));
+        container.insert("env".into(), Value::from_iter(env));
+    }
+    // Host variables and env files are provided by a Secret created from them
+    if !service.host_env.is_empty() || !service.env_files.is_empty() {
+        container.insert(
+            "envFrom".into(),
+            json!([{ "secretRef": { "name": format!("{}-env", name) } }]),
+        );
+    }
+    let volume_name = |i: usize| format!("volume-{}", i);
+    let mounts = service
+        .mounts
+        .iter()
+        .enumerate()
+        .map(|(i, (_, container, readonly))| {
+            json!({ "name": volume_name(i), "mountPath": container, "readOnly": readonly })
+        });
+    container.insert("volumeMounts".into(), Value::from_iter(mounts));
+    let volumes =
+        service.mounts.iter().enumerate().map(
+            |(i, (host, _, _))| json!({ "name": volume_name(i), "hostPath": { "path": host } }),
+        );
+    let mut pod = Map::new();
+    pod.insert("containers".into(), json!([container]));
+    pod.insert("volumes".into(), Value::from_iter(volumes));
+    if !service.groups.is_empty() {
+        pod.insert(
+            "securityContext".into(),
+            json!({ "supplementalGroups": service.groups }),
+        );
+    }
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": name, "labels": { "app": name } },
+        "spec": {
+            "replicas": 1,
+            "selector": { "matchLabels": { "app": name } },
+            "template": {
+                "metadata": { "labels": { "app": name } },
+                "spec": pod,
+            },
+        },
+    })
+}
+
+/// Path from the directory of the devcontainer files back to the project, which is the
+/// build context. `None` if the directory is outside of the project.
+fn build_context(dir: &Path, project: &Path) -> Option<String> {
+    let mut depth = 0usize;
+    for component in project.join(dir).strip_prefix(project).ok()?.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(match depth {
+        0 => ".".to_string(),
+        _ => Vec::from_iter(std::iter::repeat_n("..", depth)).join("/"),
+    })
+}
+
+/// Renders the devcontainer.json for a Dockerfile written next to it, `context` leads
+/// from there to the project directory mc2 runs in. Host variables and env files are
+/// referenced instead of copying their values.
+fn devcontainer(dockerfile: &Dockerfile, name: &str, project: &Path, context: &str) -> Value {
+    let mut json = Map::new();
+    json.insert("name".into(), name.into());
+    json.insert(
+        "build".into(),
+        json!({ "dockerfile": "Dockerfile", "context": context }),
+    );
+    // mc2 mounts the project at the same path as on the host
+    json.insert(
+        "workspaceMount".into(),
+        "source=${localWorkspaceFolder},target=${localWorkspaceFolder},type=bind".into(),
+    );
+    json.insert("workspaceFolder".into(), "${localWorkspaceFolder}".into());
+    if !dockerfile.publishes().is_empty() {
+        let ports = dockerfile.publishes().iter().map(|x| x.to_string());
+        json.insert("appPort".into(), Value::from_iter(ports));
+    }
+    if !dockerfile.volumes().is_empty() {
+        let mounts = dockerfile.volumes().iter().map(|x| mount(x, project));
+        json.insert("mounts".into(), Value::from_iter(mounts));
+    }
+    let mut env = Map::new();
+    let mut env_files = Vec::new();
+    for (k, v) in dockerfile.env() {
+        match dockerfile.env_origin(k) {
+            None => {
+                env.insert(k.clone(), v.as_str().into());
+            }
+            Some(EnvOrigin::Host) => {
+                env.insert(k.clone(), format!("${{localEnv:{}}}", k).into());
+            }
+            Some(EnvOrigin::File(path)) => {
+                let path = workspace_path(path, project);
+                if !env_files.contains(&path) {
+                    env_files.push(path);
+                }
+            }
+        }
+    }
+    if !env.is_empty() {
+        json.insert("containerEnv".into(), Value::Object(env));
+    }
+    if !env_files.is_empty() {
+        let args = env_files
+            .into_iter()
+            .flat_map(|x| [Value::from("--env-file"), Value::from(x)]);
+        json.insert("runArgs".into(), Value::from_iter(args));
+    }
+    if let Some(user) = dockerfile.user() {
+        json.insert("remoteUser".into(), user.into());
+    }
+    if !dockerfile.on_start().is_empty() {
+        json.insert(
+            "postStartCommand".into(),
+            dockerfile.on_start().join(" && ").into(),
+        );
+    }
+    Value::Object(json)
+}
+
+/// Mount in the `--mount` syntax devcontainer.json uses
+fn mount(volume: &Volume, project: &Path) -> String {
+    let mut mount = format!(
+        "source={},target={},type=bind",
+        workspace_path(&volume.host_path, project),
+        volume.machine_path.display()
+    );
+    if volume.opts.iter().any(|x| x == "ro" || x == "readonly") {
+        mount.push_str(",readonly");
+    }
+    mount
+}
+
+/// Host path relative to `${localWorkspaceFolder}` if it is inside the project
+fn workspace_path(path: &Path, project: &Path) -> String {
+    let relative = match path.is_absolute() {
+        true => path.strip_prefix(project).ok(),
+        false => Some(path.strip_prefix("./").unwrap_or(path)),
+    };
+    match relative {
+        Some(relative) => Path::new("${localWorkspaceFolder}")
+            .join(relative)
+            .display()
+            .to_string(),
+        None => path.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EnvEntry, Publish};
+    use std::str::FromStr;
+
+    fn service() -> Service {
+        Service {
+            name: "Rust_1".into(),
+            image: "mini-cross2-abc".into(),
+            project: PathBuf::from("/p"),
+            mounts: Vec::from([
+                ("/p".into(), "/p".into(), false),
+                ("/p/data".into(), "/data".into(), true),
+            ]),
+            ports: Vec::from([(None, 8080, 80)]),
+            env: Vec::from([("A".into(), "b".into())]),
+            host_env: Vec::from(["TOKEN".into()]),
+            env_files: Vec::from(["/p/.env".into()]),
+            uid: 1000,
+            gid: 100,
+            groups: Vec::from([998]),
+            init: false,
+        }
+    }
+
+    #[test]
+    fn test_compose() {
+        let json = compose(&service());
+        let spec = &json["services"]["Rust_1"];
+        assert_eq!(spec["image"], "mini-cross2-abc");
+        assert_eq!(spec["user"], "1000:100");
+        assert_eq!(spec["volumes"], json!(["/p:/p", "/p/data:/data:ro"]));
+        assert_eq!(spec["ports"], json!(["8080:80"]));
+        assert_eq!(
+            spec["environment"],
+            json!({ "A": "b", "TOKEN": "${TOKEN}" })
+        );
+        assert_eq!(spec["env_file"], json!(["/p/.env"]));
+        assert_eq!(spec["group_add"], json!([998]));
+    }
+
+    #[test]
+    fn test_k8s() {
+        let json = k8s(&service());
+        assert_eq!(json["metadata"]["name"], "rust-1");
+        let spec = &json["spec"]["template"]["spec"];
+        let container = &spec["containers"][0];
+        assert_eq!(
+            container["ports"],
+            json!([{ "containerPort": 80, "hostPort": 8080 }])
+        );
+        assert_eq!(container["env"], json!([{ "name": "A", "value": "b" }]));
+        assert_eq!(
+            container["envFrom"],
+            json!([{ "secretRef": { "name": "rust-1-env" } }])
+        );
+        assert_eq!(container["volumeMounts"][1]["mountPath"], "/data");
+        assert_eq!(container["volumeMounts"][1]["readOnly"], true);
+        assert_eq!(spec["volumes"][1]["hostPath"]["path"], "/p/data");
+        assert_eq!(
+            spec["securityContext"],
+            json!({ "supplementalGroups": [998] })
+        );
+    }
+
+    #[test]
+    fn test_build_context() {
+        let project = Path::new("/p");
+        let context = |dir: &str| build_context(Path::new(dir), project);
+        assert_eq!(context(".devcontainer").as_deref(), Some(".."));
+        assert_eq!(context("tools/.devcontainer").as_deref(), Some("../.."));
+        assert_eq!(context("/p/a/./b/../c").as_deref(), Some("../.."));
+        assert_eq!(context(".").as_deref(), Some("."));
+        assert_eq!(context("../elsewhere"), None);
+        assert_eq!(context("/tmp/out"), None);
+    }
+
+    #[test]
+    fn test_devcontainer() {
+        let mut dockerfile = Dockerfile::new();
+        dockerfile.add_publishes([Publish::from_str("8080:80").unwrap()].iter());
+        dockerfile.add_volumes(
+            [
+                Volume::from_str("/p/data:/data:ro").unwrap(),
+                Volume::from_str("/tmp:/tmp").unwrap(),
+            ]
+            .iter(),
+        );
+        dockerfile.add_env("MY_VAR", "Hello");
+        let entry = |key: &str, file: Option<&str>, host| EnvEntry {
+            key: key.into(),
+            value: Some("secret".into()),
+            file: file.map(PathBuf::from),
+            host,
+        };
+        dockerfile.set_env_entry(&entry("TOKEN", None, true));
+        dockerfile.set_env_entry(&entry("DB_PASSWORD", Some("/p/.env"), false));
+        dockerfile.set_user("dev".into());
+        dockerfile.set_on_start(Vec::from(["/hooks/a".into(), "/hooks/b".into()]));
+        let json = devcontainer(&dockerfile, "rust", Path::new("/p"), "..");
+        assert_eq!(json["name"], "rust");
+        assert_eq!(json["appPort"], json!(["8080:80"]));
+        assert_eq!(
+            json["mounts"],
+            json!([
+                "source=${localWorkspaceFolder}/data,target=/data,type=bind,readonly",
+                "source=/tmp,target=/tmp,type=bind"
+            ])
+        );
+        assert_eq!(
+            json["containerEnv"],
+            json!({ "MY_VAR": "Hello", "TOKEN": "${localEnv:TOKEN}" })
+        );
+        assert_eq!(
+            json["runArgs"],
+            json!(["--env-file", "${localWorkspaceFolder}/.env"])
+        );
+        assert_eq!(json["remoteUser"], "dev");
+        assert_eq!(json["postStartCommand"], "/hooks/a && /hooks/b");
+    }
+}
@@ -0,0 +1,403 @@
+use crate::convert::container_home;
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::{env, fs};
+
+/// Path the host's ssh agent socket is mounted to
+const SSH_AUTH_SOCK: &str = "/run/mc2/ssh-agent.sock";
+
+/// Path the docker socket is mounted to
+pub const DOCKER_SOCK: &str = "/var/run/docker.sock";
+
+/// Path the host's git credential cache socket is mounted to
+const GIT_CREDENTIAL_SOCK: &str = "/run/mc2/git-credential.sock";
+
+/// Opt-in host resources forwarded into the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Forward {
+    /// Git identity, config and credential cache
+    Git,
+}
+
+impl Forward {
+    /// Docker run arguments for this forward
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            Forward::Git => git(),
+        }
+    }
+}
+
+/// Mounts a host path read-only to the same path relative to the container home
+fn mount_home_ro(host_home: &Path, relative: &str) -> Vec<String> {
+    let path = host_home.join(relative);
+    if !path.exists() {
+        return Vec::new();
+    }
+    Vec::from([
+        "-v".to_string(),
+        format!(
+            "{}:{}:ro",
+            path.display(),
+            container_home().join(relative).display()
+        ),
+    ])
+}
+
+/// Docker run arguments forwarding the git config and the credential cache daemon
+fn git() -> Vec<String> {
+    let Some(home) = env::home_dir() else {
+        return Vec::new();
+    };
+    let mut args = mount_home_ro(&home, ".gitconfig");
+    args.extend(mount_home_ro(&home, ".config/git"));
+
+    // Socket of `git credential-cache`, see git-credential-cache(1)
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or(home.join(".cache"));
+    let socket = [
+        cache.join("git").join("credential").join("socket"),
+        home.join(".git-credential-cache").join("socket"),
+    ]
+    .into_iter()
+    .find(|x| x.exists());
+    if let Some(socket) = socket {
+        // Added on top of the helpers from the forwarded config
+        args.extend([
+            "-v".to_string(),
+            format!("{}:{}", socket.display(), GIT_CREDENTIAL_SOCK),
+            "-e".to_string(),
+            "GIT_CONFIG_COUNT=1".to_string(),
+            "-e".to_string(),
+            "GIT_CONFIG_KEY_0=credential.helper".to_string(),
+            "-e".to_string(),
+            format!("GIT_CONFIG_VALUE_0=cache --socket={}", GIT_CREDENTIAL_SOCK),
+        ]);
+    }
+    args
+}
+
+/// Docker run arguments forwarding the host's ssh agent, empty if no agent is running.
+/// With `known_hosts` the host's `~/.ssh/known_hosts` is mounted read-only as well.
+pub fn ssh_agent(known_hosts: bool) -> Vec<String> {
+    let Some(socket) = env::var_os("SSH_AUTH_SOCK").map(PathBuf::from) else {
+        return Vec::new();
+    };
+    // The variable may point to a socket of an agent that is gone
+    let Ok(metadata) = socket.metadata() else {
+        return Vec::new();
+    };
+
+    let mut args = Vec::from([
+        "-v".to_string(),
+        format!("{}:{}", socket.display(), SSH_AUTH_SOCK),
+        "-e".to_string(),
+        format!("SSH_AUTH_SOCK={}", SSH_AUTH_SOCK),
+    ]);
+    // The container user shares our uid, only a socket owned by someone else
+    // (e.g. a system wide agent) needs its group to be accessible
+    if metadata.uid() != users::get_current_uid() {
+        args.extend(["--group-add".to_string(), metadata.gid().to_string()]);
+    }
+
+    if known_hosts
+        && let Some(file) = env::home_dir().map(|x| x.join(".ssh").join("known_hosts"))
+        && file.is_file()
+    {
+        args.extend([
+            "-v".to_string(),
+            format!(
+                "{}:{}:ro",
+                file.display(),
+                container_home().join(".ssh").join("known_hosts").display()
+            ),
+        ]);
+    }
+    args
+}
+
+/// Socket of the docker daemon mc2 talks to, `DOCKER_HOST` is honored for unix sockets
+pub fn docker_socket() -> Option<PathBuf> {
+    let socket = match env::var("DOCKER_HOST") {
+        Ok(host) => PathBuf::from(host.strip_prefix("unix://")?),
+        Err(_) => PathBuf::from(DOCKER_SOCK),
+    };
+    socket.exists().then_some(socket)
+}
+
+/// Docker run arguments mounting the docker socket and adding its group, the container
+/// user shares our uid but not our groups. Sockets of the root group are refused.
+pub fn docker_socket_args() -> io::Result<Vec<String>> {
+    let socket = docker_socket().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::NotFound,
+            "'docker_socket:' is enabled but no docker socket has been found",
+        )
+    })?;
+    let gid = socket.metadata()?.gid();
+    // Membership in the root group grants far more than the socket
+    if gid == 0 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Docker socket {} belongs to the root group, the user is not added to it. \
+                 Give the socket a dedicated group like 'docker' or disable 'docker_socket:'",
+                socket.display()
+            ),
+        ));
+    }
+    Ok(Vec::from([
+        "-v".to_string(),
+        format!("{}:{}", socket.display(), DOCKER_SOCK),
+        "--group-add".to_string(),
+        gid.to_string(),
+    ]))
+}
+
+/// Mount point of the generated X11 authority file
+const XAUTHORITY: &str = "/tmp/.mc2.xauth";
+
+/// GUI related forwards, only X11 is enabled by default. Each of them is only applied
+/// if the host provides it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gui {
+    /// X11 display, socket and authority, enabled by default
+    pub x11: Option<bool>,
+    /// Wayland compositor socket, opt-in
+    pub wayland: Option<bool>,
+    /// PulseAudio and PipeWire sockets, opt-in
+    pub audio: Option<bool>,
+    /// GPU render devices in /dev/dri, opt-in
+    pub dri: Option<bool>,
+}
+
+impl Gui {
+    /// Overrides every setting `other` defines
+    pub fn merge(&mut self, other: &Gui) {
+        self.x11 = other.x11.or(self.x11);
+        self.wayland = other.wayland.or(self.wayland);
+        self.audio = other.audio.or(self.audio);
+        self.dri = other.dri.or(self.dri);
+    }
+
+    pub fn disable(&mut self) {
+        self.merge(&Gui {
+            x11: Some(false),
+            wayland: Some(false),
+            audio: Some(false),
+            dri: Some(false),
+        })
+    }
+
+    /// Docker run arguments for all enabled forwards
+    pub fn args(&self) -> Vec<String> {
+        let runtime_dir = container_runtime_dir();
+        let mut args = Vec::new();
+        if self.x11.unwrap_or(true) {
+            args.extend(x11());
+        }
+        let mut runtime = Vec::new();
+        if self.wayland.unwrap_or(false) {
+            runtime.extend(wayland(&runtime_dir));
+        }
+        if self.audio.unwrap_or(false) {
+            runtime.extend(audio(&runtime_dir));
+        }
+        if !runtime.is_empty() {
+            args.extend([
+                "-e".to_string(),
+                format!("XDG_RUNTIME_DIR={}", runtime_dir.display()),
+            ]);
+            args.extend(runtime);
+        }
+        if self.dri.unwrap_or(false) {
+            args.extend(dri());
+        }
+        args
+    }
+}
+
+/// XDG_RUNTIME_DIR inside the container
+fn container_runtime_dir() -> PathBuf {
+    PathBuf::from("/run/user").join(users::get_current_uid().to_string())
+}
+
+fn host_runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+}
+
+/// Directory only the user can access: XDG_RUNTIME_DIR or `mc2-<uid>` in the temp
+/// directory, which is refused if someone else created it
+fn private_dir() -> Option<PathBuf> {
+    if let Some(dir) = host_runtime_dir().filter(|x| x.is_dir()) {
+        return Some(dir);
+    }
+    let uid = users::get_current_uid();
+    let dir = env::temp_dir().join(format!("mc2-{}", uid));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => Some(dir),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let metadata = fs::symlink_metadata(&dir).ok()?;
+            let private =
+                metadata.is_dir() && metadata.uid() == uid && metadata.mode() & 0o077 == 0;
+            private.then_some(dir)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Writes an authority file for `display` whose entries match any hostname, so
+/// the container does not need `xhost +`. Returns `None` if xauth is not available.
+fn xauthority(display: &str) -> Option<PathBuf> {
+    let list = process::Command::new("xauth")
+        .args(["nlist", display])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !list.status.success() || list.stdout.is_empty() {
+        return None;
+    }
+    // The first 4 hex digits are the address family, ffff is FamilyWild
+    let list = String::from_utf8_lossy(&list.stdout)
+        .lines()
+        .filter(|line| line.len() > 4)
+        .map(|line| format!("ffff{}\n", &line[4..]))
+        .collect::<String>();
+
+    let path = private_dir()?.join("mc2.xauth");
+    let _ = fs::remove_file(&path);
+    let mut merge = process::Command::new("xauth")
+        .args(["-f", &path.to_string_lossy(), "nmerge", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    merge.stdin.take()?.write_all(list.as_bytes()).ok()?;
+    merge.wait().ok()?.success().then_some(path)
+}
+
+fn x11() -> Vec<String> {
+    let Ok(display) = env::var("DISPLAY") else {
+        return Vec::new();
+    };
+    let mut args = Vec::from([
+        "-e".to_string(),
+        format!("DISPLAY={}", display),
+        "-v".to_string(),
+        "/tmp/.X11-unix:/tmp/.X11-unix".to_string(),
+    ]);
+    if let Some(xauthority) = xauthority(&display) {
+        args.extend([
+            "-v".to_string(),
+            format!("{}:{}:ro", xauthority.display(), XAUTHORITY),
+            "-e".to_string(),
+            format!("XAUTHORITY={}", XAUTHORITY),
+        ]);
+    }
+    args
+}
+
+fn wayland(runtime_dir: &Path) -> Vec<String> {
+    let Some(display) = env::var_os("WAYLAND_DISPLAY").map(PathBuf::from) else {
+        return Vec::new();
+    };
+    // WAYLAND_DISPLAY is either a socket name in XDG_RUNTIME_DIR or an absolute path
+    let socket = match host_runtime_dir() {
+        Some(dir) if display.is_relative() => dir.join(&display),
+        _ => display,
+    };
+    let (Some(name), true) = (socket.file_name(), socket.exists()) else {
+        return Vec::new();
+    };
+    Vec::from([
+        "-v".to_string(),
+        format!("{}:{}", socket.display(), runtime_dir.join(name).display()),
+        "-e".to_string(),
+        format!("WAYLAND_DISPLAY={}", name.to_string_lossy()),
+    ])
+}
+
+fn audio(runtime_dir: &Path) -> Vec<String> {
+    let Some(host_dir) = host_runtime_dir() else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    let pulse = host_dir.join("pulse").join("native");
+    if pulse.exists() {
+        let target = runtime_dir.join("pulse").join("native");
+        args.extend([
+            "-v".to_string(),
+            format!("{}:{}", pulse.display(), target.display()),
+            "-e".to_string(),
+            format!("PULSE_SERVER=unix:{}", target.display()),
+        ]);
+    }
+    let pipewire = host_dir.join("pipewire-0");
+    if pipewire.exists() {
+        args.extend([
+            "-v".to_string(),
+            format!(
+                "{}:{}",
+                pipewire.display(),
+                runtime_dir.join("pipewire-0").display()
+            ),
+        ]);
+    }
+    args
+}
+
+fn dri() -> Vec<String> {
+    let dri = Path::new("/dev/dri");
+    if !dri.is_dir() {
+        return Vec::new();
+    }
+    let mut args = Vec::from(["--device".to_string(), dri.display().to_string()]);
+    // Render nodes are usually owned by the render or video group
+    let mut gids = fs::read_dir(dri)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .map(|metadata| metadata.gid())
+        .filter(|gid| *gid != 0)
+        .collect::<Vec<_>>();
+    gids.sort();
+    gids.dedup();
+    for gid in gids {
+        args.extend(["--group-add".to_string(), gid.to_string()]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gui_merge() {
+        let mut gui = Gui::default();
+        gui.merge(&Gui {
+            audio: Some(false),
+            ..Gui::default()
+        });
+        gui.merge(&Gui {
+            x11: Some(true),
+            ..Gui::default()
+        });
+        assert_eq!(
+            gui,
+            Gui {
+                x11: Some(true),
+                wayland: None,
+                audio: Some(false),
+                dri: None,
+            }
+        );
+        gui.disable();
+        assert!(gui.args().is_empty());
+    }
+}
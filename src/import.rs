@@ -0,0 +1,964 @@
+use crate::config::{Publish, Volume};
+use serde_json::Value;
+use serde_yaml::Mapping;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Something of the imported file that has no equivalent in a toolchain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub path: PathBuf,
+    /// Line of the Dockerfile instruction, devcontainer.json warnings name the key instead
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        write!(f, ": warning: {}", self.message)
+    }
+}
+
+/// Toolchain translated from a Dockerfile or devcontainer.json
+#[derive(Debug, Default)]
+pub struct Toolchain {
+    pub base: Option<String>,
+    pub install: Vec<String>,
+    pub publish: Vec<Publish>,
+    /// Relative host paths are relative to the project
+    pub volume: Vec<Volume>,
+    pub env: Vec<(String, String)>,
+    pub passthrough: Vec<String>,
+    pub workdir: Option<String>,
+    pub shell: Option<Vec<String>>,
+    pub entrypoint: Option<Value>,
+    pub healthcheck: Option<Value>,
+    pub labels: Vec<(String, String)>,
+    pub on_start: Vec<String>,
+    pub on_enter: Vec<String>,
+    /// Commands of the script section, they run as root like RUN does
+    pub script: Vec<String>,
+    pub warnings: Vec<Warning>,
+    /// `export` lines of ENV instructions, added before the next script command
+    exports: Vec<String>,
+}
+
+impl Toolchain {
+    /// Imports a devcontainer.json if the file ends with `.json`, a Dockerfile otherwise
+    pub fn import(path: &Path) -> io::Result<Self> {
+        let mut toolchain = Toolchain::default();
+        match path.extension().is_some_and(|x| x == "json") {
+            true => toolchain.import_devcontainer(path)?,
+            false => toolchain.import_dockerfile(path)?,
+        }
+        Ok(toolchain)
+    }
+
+    /// Renders the toolchain file written to `dir`, both `dir` and `project` are absolute
+    pub fn render(&self, project: &Path, dir: &Path) -> io::Result<String> {
+        let mut yaml = Mapping::new();
+        let mut insert = |key: &str, value: serde_yaml::Value| {
+            yaml.insert(key.into(), value);
+        };
+        let strings = |values: &[String]| serde_yaml::Value::from(values.to_vec());
+        if let Some(base) = &self.base {
+            insert("base", base.clone().into());
+        }
+        if !self.install.is_empty() {
+            insert("install", strings(&self.install));
+        }
+        if !self.publish.is_empty() {
+            insert("publish", to_yaml(&self.publish)?);
+        }
+        if !self.volume.is_empty() {
+            let volume = self
+                .volume
+                .iter()
+                .map(|volume| match volume.host_path.is_relative() {
+                    true => Volume {
+                        host_path: from_toolchain(&volume.host_path, project, dir),
+                        ..volume.clone()
+                    },
+                    false => volume.clone(),
+                });
+            insert("volume", to_yaml(&Vec::from_iter(volume))?);
+        }
+        if !self.env.is_empty() {
+            let env = self
+                .env
+                .iter()
+                .map(|(k, v)| (k.clone().into(), v.clone().into()));
+            insert("env", serde_yaml::Value::Mapping(Mapping::from_iter(env)));
+        }
+        if !self.passthrough.is_empty() {
+            insert("passthrough", strings(&self.passthrough));
+        }
+        if let Some(workdir) = &self.workdir {
+            insert("workdir", workdir.clone().into());
+        }
+        if let Some(shell) = &self.shell {
+            insert("shell", strings(shell));
+        }
+        if let Some(entrypoint) = &self.entrypoint {
+            insert("entrypoint", to_yaml(entrypoint)?);
+        }
+        if let Some(healthcheck) = &self.healthcheck {
+            insert("healthcheck", to_yaml(healthcheck)?);
+        }
+        if !self.labels.is_empty() {
+            let labels = self
+                .labels
+                .iter()
+                .map(|(k, v)| (k.clone().into(), v.clone().into()));
+            insert(
+                "labels",
+                serde_yaml::Value::Mapping(Mapping::from_iter(labels)),
+            );
+        }
+        if !self.on_start.is_empty() || !self.on_enter.is_empty() {
+            let mut hooks = Mapping::new();
+            if !self.on_start.is_empty() {
+                hooks.insert("on_start".into(), self.on_start.join("\n").into());
+            }
+            if !self.on_enter.is_empty() {
+                hooks.insert("on_enter".into(), self.on_enter.join("\n").into());
+            }
+            insert("hooks", serde_yaml::Value::Mapping(hooks));
+        }
+        if !self.script.is_empty() {
+            insert("script_user", "root".into());
+        }
+
+        let mut result = String::from("---\n");
+        if !yaml.is_empty() {
+            result.push_str(&serde_yaml::to_string(&yaml).map_err(invalid_data)?);
+        }
+        result.push_str("---\n#!/bin/sh\n");
+        if !self.script.is_empty() {
+            // A failing RUN fails the build
+            result.push_str("set -e\n\n");
+            for command in &self.script {
+                result.push_str(command);
+                result.push('\n');
+            }
+        }
+        Ok(result)
+    }
+
+    fn warn(&mut self, path: &Path, line: Option<usize>, message: String) {
+        self.warnings.push(Warning {
+            path: path.to_path_buf(),
+            line,
+            message,
+        })
+    }
+
+    fn set_env(&mut self, key: String, value: String) {
+        self.env.retain(|(k, _)| *k != key);
+        self.env.push((key, value));
+    }
+
+    /// Adds a command to the script, after the variables RUN would have seen
+    fn add_script(&mut self, command: String) {
+        self.script.append(&mut self.exports);
+        self.script.push(command);
+    }
+
+    fn import_dockerfile(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        for instruction in instructions(&content) {
+            let Instruction {
+                line,
+                keyword,
+                args,
+            } = instruction;
+            let warn = |this: &mut Self, message: String| this.warn(path, Some(line), message);
+            match keyword.as_str() {
+                "FROM" => {
+                    let args = args
+                        .split_whitespace()
+                        .filter(|x| !x.starts_with("--"))
+                        .collect::<Vec<_>>();
+                    if let Some(base) = &self.base {
+                        // Only the last stage ends up in the image
+                        warn(self, format!("Build stage from {} is dropped", base));
+                        let warnings = std::mem::take(&mut self.warnings);
+                        *self = Toolchain {
+                            warnings,
+                            ..Toolchain::default()
+                        };
+                    }
+                    self.base = args.first().map(|x| x.to_string());
+                }
+                "RUN" => self.import_run(path, line, &args),
+                "ENV" => {
+                    for (key, value) in key_values(&args) {
+                        // Later RUNs see the variable, they end up in the script
+                        match value.contains('$') {
+                            true => {
+                                let value = value
+                                    .replace('\\', "\\\\")
+                                    .replace('"', "\\\"")
+                                    .replace('`', "\\`");
+                                self.exports.push(format!("export {}=\"{}\"", key, value));
+                                warn(
+                                    self,
+                                    format!(
+                                        "ENV {} references variables and is not imported, \
+                                         later RUNs get it exported in the script",
+                                        key
+                                    ),
+                                );
+                            }
+                            false => {
+                                self.exports
+                                    .push(format!("export {}={}", key, quote(&value)));
+                                self.set_env(key, value);
+                            }
+                        }
+                    }
+                }
+                "LABEL" => {
+                    for (key, value) in key_values(&args) {
+                        self.labels.retain(|(k, _)| *k != key);
+                        self.labels.push((key, value));
+                    }
+                }
+                "EXPOSE" => {
+                    for port in args.split_whitespace() {
+                        match port.trim_end_matches("/tcp").parse::<u16>() {
+                            Ok(port) => self.publish.push(Publish {
+                                host_ip: None,
+                                host_port: port,
+                                machine_port: port,
+                            }),
+                            Err(_) => warn(self, format!("EXPOSE {} is not imported", port)),
+                        }
+                    }
+                }
+                "WORKDIR" => self.workdir = Some(args),
+                "SHELL" => match serde_json::from_str(&args) {
+                    Ok(shell) => self.shell = Some(shell),
+                    Err(_) => warn(self, format!("Invalid SHELL {}", args)),
+                },
+                "ENTRYPOINT" => self.entrypoint = Some(command_line(&args)),
+                "HEALTHCHECK" => {
+                    let (options, command) = match args.find("CMD") {
+                        Some(i) => (args[..i].trim(), Some(args[i + 3..].trim())),
+                        None => (args.as_str(), None),
+                    };
+                    if !options.is_empty() && options != "NONE" {
+                        warn(self, format!("HEALTHCHECK options {} are dropped", options));
+                    }
+                    self.healthcheck = command.map(command_line);
+                }
+                "CMD" => warn(
+                    self,
+                    "CMD is dropped, mc2 runs the given command or bash".into(),
+                ),
+                "USER" => warn(
+                    self,
+                    format!("USER {} is dropped, mc2 runs as the host user", args),
+                ),
+                "COPY" | "ADD" => warn(
+                    self,
+                    format!(
+                        "{} is not imported, files next to the toolchain are copied to /",
+                        keyword
+                    ),
+                ),
+                "ARG" => warn(
+                    self,
+                    format!("ARG {} is not supported, substitute it in the script", args),
+                ),
+                _ => warn(self, format!("{} is not imported", keyword)),
+            }
+        }
+        if self.base.is_none() {
+            self.warn(path, None, "No FROM found".into());
+        }
+        Ok(())
+    }
+
+    /// Package installs become `install:` as long as nothing else ran before them,
+    /// updates and cleanups are dropped since mc2 does them itself
+    fn import_run(&mut self, path: &Path, line: usize, args: &str) {
+        let mut args = args.trim();
+        while args.starts_with("--") {
+            let (flag, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            self.warn(path, Some(line), format!("RUN {} is dropped", flag));
+            args = rest.trim_start();
+        }
+        if let Some(heredoc) = args.strip_prefix("<<") {
+            // Body of the heredoc is the script
+            let body = heredoc.split_once('\n').map(|x| x.1).unwrap_or_default();
+            let body = body.rsplit_once('\n').map(|x| x.0).unwrap_or_default();
+            self.add_script(body.to_string());
+            return;
+        }
+        if args.starts_with('[') {
+            match serde_json::from_str::<Vec<String>>(args) {
+                Ok(command) => {
+                    let command = command.iter().map(|x| quote(x)).collect::<Vec<_>>();
+                    self.add_script(command.join(" "));
+                }
+                Err(_) => self.warn(path, Some(line), format!("Invalid RUN {}", args)),
+            }
+            return;
+        }
+        if args.contains("<<") {
+            self.add_script(args.to_string());
+            return;
+        }
+        for command in split_and(args) {
+            if !self.script.is_empty() {
+                self.add_script(command);
+                continue;
+            }
+            match package_command(&command) {
+                Some(PackageCommand::Install(packages)) => {
+                    for package in packages {
+                        if !self.install.contains(&package) {
+                            self.install.push(package);
+                        }
+                    }
+                }
+                Some(PackageCommand::Housekeeping) => {}
+                None => self.add_script(command),
+            }
+        }
+    }
+
+    fn import_devcontainer(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        let json: Value = serde_json::from_str(&strip_jsonc(&content)).map_err(invalid_data)?;
+        let Value::Object(json) = json else {
+            return Err(invalid_data("devcontainer.json is not an object"));
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let warn = |this: &mut Self, message: String| this.warn(path, None, message);
+
+        if let Some(build) = json.get("build") {
+            let dockerfile = build
+                .get("dockerfile")
+                .or(build.get("dockerFile"))
+                .and_then(Value::as_str)
+                .unwrap_or("Dockerfile");
+            self.import_dockerfile(&dir.join(dockerfile))?;
+            if build.get("args").is_some() {
+                warn(self, "'build.args' is not imported".into());
+            }
+        }
+        if let Some(image) = json.get("image").and_then(Value::as_str) {
+            self.base = Some(image.to_string());
+        }
+        // Lifecycle commands in the order they run, mc2 containers are created per run
+        for key in [
+            "onCreateCommand",
+            "updateContentCommand",
+            "postCreateCommand",
+            "postStartCommand",
+        ] {
+            if let Some(command) = json.get(key) {
+                self.on_start.extend(lifecycle_command(command));
+            }
+        }
+        if let Some(command) = json.get("postAttachCommand") {
+            self.on_enter.extend(lifecycle_command(command));
+        }
+
+        for (key, value) in json.iter() {
+            match key.as_str() {
+                "name"
+                | "$schema"
+                | "image"
+                | "build"
+                | "workspaceFolder"
+                | "workspaceMount"
+                | "onCreateCommand"
+                | "updateContentCommand"
+                | "postCreateCommand"
+                | "postStartCommand"
+                | "postAttachCommand" => {}
+                "forwardPorts" | "appPort" => {
+                    let ports = match value {
+                        Value::Array(ports) => ports.clone(),
+                        port => Vec::from([port.clone()]),
+                    };
+                    for port in ports {
+                        let publish = match &port {
+                            Value::Number(port) => port.as_u64().map(|x| format!("{x}:{x}")),
+                            Value::String(port) => Some(port.clone()),
+                            _ => None,
+                        };
+                        match publish.and_then(|x| Publish::from_str(&x).ok()) {
+                            Some(publish) => self.publish.push(publish),
+                            None => warn(self, format!("Port {} is not imported", port)),
+                        }
+                    }
+                }
+                "containerEnv" | "remoteEnv" => {
+                    for (key, value) in value.as_object().into_iter().flatten() {
+                        let value = value.as_str().unwrap_or_default();
+                        let local = value
+                            .strip_prefix("${localEnv:")
+                            .and_then(|x| x.strip_suffix('}'));
+                        match local {
+                            Some(local) if local == key => self.passthrough.push(key.clone()),
+                            _ if value.contains("${") => warn(
+                                self,
+                                format!("Variable {} is not imported, it uses {}", key, value),
+                            ),
+                            _ => self.set_env(key.clone(), value.to_string()),
+                        }
+                    }
+                }
+                "mounts" => {
+                    for mount in value.as_array().into_iter().flatten() {
+                        match volume(mount) {
+                            Some(volume) => self.volume.push(volume),
+                            None => warn(self, format!("Mount {} is not imported", mount)),
+                        }
+                    }
+                }
+                "remoteUser" | "containerUser" => warn(
+                    self,
+                    format!("'{}' is dropped, mc2 runs as the host user", key),
+                ),
+                key => warn(self, format!("'{}' is not imported", key)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Instruction of a Dockerfile, continued lines and heredocs are joined
+#[derive(Debug, PartialEq, Eq)]
+struct Instruction {
+    line: usize,
+    keyword: String,
+    args: String,
+}
+
+fn instructions(content: &str) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut lines = content.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut text = String::new();
+        let mut current = line;
+        loop {
+            match current.trim_end().strip_suffix('\\') {
+                Some(continued) => {
+                    text.push_str(continued);
+                    // Comments within continued lines are removed
+                    current = loop {
+                        match lines.next() {
+                            Some((_, x)) if x.trim().starts_with('#') => continue,
+                            Some((_, x)) => break x,
+                            None => break "",
+                        }
+                    };
+                }
+                None => {
+                    text.push_str(current);
+                    break;
+                }
+            }
+        }
+        if let Some(delimiter) = heredoc_delimiter(&text) {
+            for (_, line) in lines.by_ref() {
+                text.push('\n');
+                text.push_str(line);
+                if line.trim() == delimiter {
+                    break;
+                }
+            }
+        }
+        let text = text.trim();
+        let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        result.push(Instruction {
+            line: i + 1,
+            keyword: keyword.to_uppercase(),
+            args: args.trim().to_string(),
+        });
+    }
+    result
+}
+
+/// Delimiter of the first heredoc of an instruction
+fn heredoc_delimiter(text: &str) -> Option<String> {
+    let (_, rest) = text.split_once("<<")?;
+    let rest = rest.trim_start_matches('-');
+    let delimiter = rest
+        .split(|x: char| x.is_whitespace() || x == '>')
+        .next()?
+        .trim_matches(['"', '\'']);
+    (!delimiter.is_empty()).then(|| delimiter.to_string())
+}
+
+/// Splits a shell command at `&&` outside of quotes
+fn split_and(command: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '&') if chars.peek() == Some(&'&') => {
+                chars.next();
+                result.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+        current.push(c);
+    }
+    result.push(current.trim().to_string());
+    result.retain(|x| !x.is_empty());
+    result
+}
+
+/// Splits at whitespace outside of quotes and removes the quotes
+fn words(s: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => result.extend(current.take()),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_default();
+            }
+            (Some(q), c) if q == c => quote = None,
+            (Some('"') | None, '\\') => current.get_or_insert_default().extend(chars.next()),
+            (_, c) => current.get_or_insert_default().push(c),
+        }
+    }
+    result.extend(current);
+    result
+}
+
+/// `key=value` pairs of ENV and LABEL, or the legacy `key value` form
+fn key_values(args: &str) -> Vec<(String, String)> {
+    let words = words(args);
+    match words.first() {
+        Some(first) if !first.contains('=') => {
+            let value = args.trim()[first.len()..].trim();
+            Vec::from([(first.clone(), value.to_string())])
+        }
+        _ => words
+            .iter()
+            .filter_map(|x| x.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    }
+}
+
+/// Exec form of ENTRYPOINT and HEALTHCHECK as list, the shell form as string
+fn command_line(args: &str) -> Value {
+    match serde_json::from_str::<Vec<String>>(args) {
+        Ok(args) => Value::from(args),
+        Err(_) => Value::from(args),
+    }
+}
+
+/// Single quotes an argument if the shell would split or expand it
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "-_.,:/@%+=".contains(x));
+    match plain {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PackageCommand {
+    Install(Vec<String>),
+    /// Updates, upgrades and cleanups mc2 does itself
+    Housekeeping,
+}
+
+/// Recognizes installs, updates and cleanups of the supported package managers
+fn package_command(command: &str) -> Option<PackageCommand> {
+    let words = command
+        .split_whitespace()
+        .skip_while(|x| *x == "sudo" || (x.contains('=') && !x.starts_with('-')))
+        .collect::<Vec<_>>();
+    let (tool, args) = words.split_first()?;
+    if *tool == "rm" {
+        let cache = |x: &&str| x.starts_with("/var/lib/apt/lists") || x.starts_with("/var/cache/");
+        let paths = args.iter().filter(|x| !x.starts_with('-'));
+        return (paths.clone().count() > 0 && paths.clone().all(cache))
+            .then_some(PackageCommand::Housekeeping);
+    }
+    if args
+        .iter()
+        .any(|x| x.contains(['|', ';', '>', '<', '$', '`', '(', '*']))
+    {
+        return None;
+    }
+    // Options of apt taking a value
+    let mut skip = false;
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    for arg in args {
+        if skip {
+            skip = false;
+        } else if arg.starts_with('-') {
+            skip = matches!(*arg, "-o" | "-t" | "--option" | "--target-release");
+            options.push(*arg);
+        } else {
+            positional.push(arg.to_string());
+        }
+    }
+    let packages = |positional: &[String]| PackageCommand::Install(positional.to_vec());
+    match *tool {
+        "apt" | "apt-get" | "aptitude" => match positional.split_first()? {
+            (verb, rest) if verb == "install" => Some(packages(rest)),
+            (verb, _)
+                if [
+                    "update",
+                    "upgrade",
+                    "dist-upgrade",
+                    "full-upgrade",
+                    "clean",
+                    "autoclean",
+                    "autoremove",
+                ]
+                .contains(&verb.as_str()) =>
+            {
+                Some(PackageCommand::Housekeeping)
+            }
+            _ => None,
+        },
+        "dnf" | "yum" | "microdnf" => match positional.split_first()? {
+            (verb, rest) if verb == "install" => Some(packages(rest)),
+            (verb, rest) if rest.is_empty() || verb == "clean" || verb == "makecache" => {
+                ["update", "upgrade", "clean", "makecache", "check-update"]
+                    .contains(&verb.as_str())
+                    .then_some(PackageCommand::Housekeeping)
+            }
+            _ => None,
+        },
+        "apk" => match positional.split_first()? {
+            (verb, rest) if verb == "add" => Some(packages(rest)),
+            (verb, []) if verb == "update" || verb == "upgrade" => {
+                Some(PackageCommand::Housekeeping)
+            }
+            _ => None,
+        },
+        "zypper" => match positional.split_first()? {
+            (verb, rest) if verb == "install" || verb == "in" => Some(packages(rest)),
+            (verb, _)
+                if [
+                    "refresh",
+                    "ref",
+                    "update",
+                    "up",
+                    "dist-upgrade",
+                    "dup",
+                    "clean",
+                ]
+                .contains(&verb.as_str()) =>
+            {
+                Some(PackageCommand::Housekeeping)
+            }
+            _ => None,
+        },
+        "pacman" => {
+            let sync = options
+                .iter()
+                .find(|x| x.starts_with("-S") || **x == "--sync")?;
+            match positional.is_empty() {
+                false if !sync.contains('c') => Some(packages(&positional)),
+                true => Some(PackageCommand::Housekeeping),
+                false => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Lifecycle command of devcontainer.json, an object holds commands run in parallel
+fn lifecycle_command(command: &Value) -> Vec<String> {
+    match command {
+        Value::String(command) => Vec::from([command.clone()]),
+        Value::Array(args) => {
+            let args = args
+                .iter()
+                .map(|x| quote(x.as_str().unwrap_or_default()))
+                .collect::<Vec<_>>();
+            Vec::from([args.join(" ")])
+        }
+        Value::Object(commands) => commands.values().flat_map(lifecycle_command).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Bind mount of devcontainer.json, in `--mount` syntax or as object
+fn volume(mount: &Value) -> Option<Volume> {
+    let mut source = None;
+    let mut target = None;
+    let mut kind = None;
+    let mut readonly = false;
+    match mount {
+        Value::String(mount) => {
+            for option in mount.split(',') {
+                let (key, value) = option.split_once('=').unwrap_or((option, ""));
+                match key {
+                    "source" | "src" => source = Some(value.to_string()),
+                    "target" | "dst" | "destination" => target = Some(value.to_string()),
+                    "type" => kind = Some(value.to_string()),
+                    "readonly" | "ro" => readonly = value != "false",
+                    _ => {}
+                }
+            }
+        }
+        Value::Object(mount) => {
+            let get = |key: &str| mount.get(key).and_then(Value::as_str).map(String::from);
+            source = get("source");
+            target = get("target");
+            kind = get("type");
+        }
+        _ => return None,
+    }
+    if kind.as_deref() != Some("bind") {
+        return None;
+    }
+    let source = source?;
+    let source = match source.strip_prefix("${localWorkspaceFolder}") {
+        Some(path) => format!(".{}", path),
+        None if source.contains("${") => return None,
+        None => source,
+    };
+    Some(Volume {
+        host_path: PathBuf::from(source),
+        machine_path: PathBuf::from(target?),
+        opts: match readonly {
+            true => Vec::from(["ro".into()]),
+            false => Vec::new(),
+        },
+    })
+}
+
+/// Project relative `path` as seen from the toolchain in `dir`, mc2 resolves volumes
+/// against the directory of the toolchain. Absolute if `dir` is outside of the project.
+fn from_toolchain(path: &Path, project: &Path, dir: &Path) -> PathBuf {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    let Ok(dir) = dir.strip_prefix(project) else {
+        return project.join(path);
+    };
+    let mut result = PathBuf::new();
+    for component in dir.components() {
+        match component {
+            Component::Normal(_) => result.push(".."),
+            Component::CurDir => {}
+            _ => return project.join(path),
+        }
+    }
+    match result.as_os_str().is_empty() {
+        true => Path::new(".").join(path),
+        false => result.join(path),
+    }
+}
+
+/// Removes the comments and trailing commas JSON with comments allows
+fn strip_jsonc(content: &str) -> String {
+    let mut result = String::new();
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            result.push(c);
+            match c {
+                '\\' => result.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                result.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|x| *x != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            (']' | '}', _) => {
+                let end = result.trim_end();
+                if end.ends_with(',') {
+                    result.truncate(end.len() - 1);
+                }
+                result.push(c);
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn to_yaml<T: serde::Serialize>(value: &T) -> io::Result<serde_yaml::Value> {
+    serde_yaml::to_value(value).map_err(invalid_data)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MixinYaml;
+
+    fn write(name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mc2-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_package_command() {
+        assert_eq!(
+            package_command(
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends gcc make"
+            ),
+            Some(PackageCommand::Install(Vec::from([
+                "gcc".into(),
+                "make".into()
+            ])))
+        );
+        assert_eq!(
+            package_command("apk add --no-cache git"),
+            Some(PackageCommand::Install(Vec::from(["git".into()])))
+        );
+        assert_eq!(
+            package_command("pacman -Syu --noconfirm cmake"),
+            Some(PackageCommand::Install(Vec::from(["cmake".into()])))
+        );
+        assert_eq!(
+            package_command("rm -rf /var/lib/apt/lists/*"),
+            Some(PackageCommand::Housekeeping)
+        );
+        assert_eq!(
+            package_command("apt-get update"),
+            Some(PackageCommand::Housekeeping)
+        );
+        assert_eq!(package_command("apt-get install -y $(cat packages)"), None);
+        assert_eq!(package_command("make install"), None);
+    }
+
+    #[test]
+    fn test_dockerfile() {
+        let path = write(
+            "multi.Dockerfile",
+            "FROM rust:1 AS build\n\
+             RUN cargo build\n\
+             FROM debian:trixie-slim\n\
+             # Packages\n\
+             RUN apt-get update && \\\n    apt-get install -y gcc \\\n    make && \\\n    \
+             rm -rf /var/lib/apt/lists/*\n\
+             RUN curl -o /tmp/x https://example.com && apt-get install -y ./x.deb\n\
+             ENV A=1 B=\"two words\"\n\
+             ENV PATH=/opt/x/bin:$PATH\n\
+             RUN x-setup\n\
+             EXPOSE 8080 53/udp\n\
+             WORKDIR /src\n\
+             COPY . /src\n\
+             ENTRYPOINT [\"/entrypoint.sh\"]\n",
+        );
+        let toolchain = Toolchain::import(&path).unwrap();
+        assert_eq!(toolchain.base.as_deref(), Some("debian:trixie-slim"));
+        assert_eq!(toolchain.install, ["gcc", "make"]);
+        assert_eq!(
+            toolchain.script,
+            [
+                "curl -o /tmp/x https://example.com",
+                "apt-get install -y ./x.deb",
+                "export A=1",
+                "export B='two words'",
+                "export PATH=\"/opt/x/bin:$PATH\"",
+                "x-setup",
+            ]
+        );
+        assert_eq!(
+            toolchain.env,
+            [("A".into(), "1".into()), ("B".into(), "two words".into())]
+        );
+        let lines = toolchain
+            .warnings
+            .iter()
+            .map(|x| x.line.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [3, 11, 13, 15]);
+
+        let rendered = toolchain.render(Path::new("/p"), Path::new("/p")).unwrap();
+        let (frontmatter, script) = rendered[4..].split_once("---\n").unwrap();
+        let yaml: MixinYaml = serde_yaml::from_str(frontmatter).unwrap();
+        assert_eq!(yaml.publish.unwrap()[0].to_string(), "8080:8080");
+        assert_eq!(yaml.workdir.as_deref(), Some("/src"));
+        assert!(yaml.entrypoint.is_some());
+        assert!(script.starts_with("#!/bin/sh\nset -e\n"));
+    }
+
+    #[test]
+    fn test_devcontainer() {
+        write("Dockerfile", "FROM fedora:40\nRUN dnf install -y gcc\n");
+        let path = write(
+            "devcontainer.json",
+            r#"{
+                // Built from the Dockerfile next to it
+                "name": "dev",
+                "build": { "dockerfile": "Dockerfile" },
+                "forwardPorts": [3000, "8080:80"],
+                "containerEnv": { "A": "b", "TOKEN": "${localEnv:TOKEN}" },
+                "mounts": ["source=${localWorkspaceFolder}/data,target=/data,type=bind,readonly"],
+                "postCreateCommand": ["npm", "install"],
+                "features": {},
+            }"#,
+        );
+        let toolchain = Toolchain::import(&path).unwrap();
+        assert_eq!(toolchain.base.as_deref(), Some("fedora:40"));
+        assert_eq!(toolchain.install, ["gcc"]);
+        let publish = toolchain.publish.iter().map(|x| x.to_string());
+        assert_eq!(publish.collect::<Vec<_>>(), ["3000:3000", "8080:80"]);
+        assert_eq!(toolchain.env, [("A".into(), "b".into())]);
+        assert_eq!(toolchain.passthrough, ["TOKEN"]);
+        assert_eq!(toolchain.volume[0].to_string(), "./data:/data:ro");
+        let rendered = |dir: &str| toolchain.render(Path::new("/p"), Path::new(dir)).unwrap();
+        assert!(rendered("/p").contains("- ./data:/data:ro\n"));
+        assert!(rendered("/p/.mc").contains("- ../data:/data:ro\n"));
+        assert!(rendered("/elsewhere").contains("- /p/data:/data:ro\n"));
+        assert_eq!(toolchain.on_start, ["npm install"]);
+        assert_eq!(toolchain.warnings.len(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::Mixin;
+use crate::convert::{PackageManager, context_dir};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_BASE: &str = "debian:trixie-slim";
+
+const IGNORE_FILE: &str = "# Files next to the toolchain that are not copied into the image\n";
+
+/// Starter toolchains, all of them are Debian based
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Template {
+    Rust,
+    Node,
+    Python,
+    Cmake,
+    EmbeddedArm,
+}
+
+impl Template {
+    const fn install(&self) -> &'static [&'static str] {
+        match self {
+            Template::Rust => &["gcc", "curl", "ca-certificates", "pkg-config", "libssl-dev"],
+            Template::Node => &["nodejs", "npm"],
+            Template::Python => &["python3", "python3-pip", "python3-venv"],
+            Template::Cmake => &["build-essential", "cmake", "ninja-build", "git"],
+            Template::EmbeddedArm => &[
+                "gcc-arm-none-eabi",
+                "libnewlib-arm-none-eabi",
+                "gdb-multiarch",
+                "openocd",
+                "cmake",
+                "make",
+            ],
+        }
+    }
+
+    const fn script(&self) -> &'static str {
+        match self {
+            Template::Rust => {
+                "# Install Rust\n\
+                 curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y\n"
+            }
+            Template::Node => "# npm install -g <package>\n",
+            Template::Python => "# pip install --user <package>\n",
+            Template::Cmake => "",
+            Template::EmbeddedArm => "",
+        }
+    }
+}
+
+pub struct Init {
+    pub machine: Option<String>,
+    pub base: Option<String>,
+    pub install: Vec<String>,
+    pub template: Option<Template>,
+    /// Toolchain path registered in `.mc2aliases.yaml`, without extension
+    pub alias: Option<PathBuf>,
+    pub ignore: bool,
+    pub force: bool,
+}
+
+impl Init {
+    /// Renders the toolchain file
+    pub fn render(&self) -> String {
+        let base = self.base.as_deref().unwrap_or(DEFAULT_BASE);
+        let mut install = Vec::from_iter(
+            self.template
+                .iter()
+                .flat_map(|x| x.install())
+                .map(|x| x.to_string()),
+        );
+        for package in &self.install {
+            if !install.contains(package) {
+                install.push(package.clone());
+            }
+        }
+
+        let mut result = format!("---\nbase: {}\n", base);
+        if !install.is_empty() {
+            result.push_str("install:\n");
+            for package in install {
+                result.push_str(&format!("  - {}\n", package));
+            }
+        }
+        result.push_str("---\n#!/bin/sh\n\n");
+        if let Some(template) = self.template {
+            result.push_str(template.script());
+        }
+        result
+    }
+
+    /// Path of the toolchain file
+    pub fn path(&self) -> PathBuf {
+        match (&self.alias, &self.machine) {
+            (Some(alias), _) => alias.with_extension("yaml"),
+            // Only a directory of its own has context files, which .mc2ignore filters
+            (None, Some(machine)) if machine != "_" => {
+                Mixin::lookup_paths_named(machine)[if self.ignore { 2 } else { 1 }].clone()
+            }
+            _ => Mixin::lookup_path_unnamed()[0].clone(),
+        }
+    }
+
+    /// Rejects options that do not fit together
+    fn validate(&self) -> io::Result<()> {
+        let base = self.base.as_deref().unwrap_or(DEFAULT_BASE);
+        let package_manager = PackageManager::from_str(base)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        if let Some(template) = self.template
+            && !matches!(package_manager, PackageManager::APT)
+        {
+            let name = template.to_possible_value().unwrap();
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Template {} installs Debian packages and needs a Debian or Ubuntu base, not {}",
+                    name.get_name(),
+                    base
+                ),
+            ));
+        }
+
+        if self.alias.is_some() && self.machine.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--alias requires a machine name",
+            ));
+        }
+        if self.ignore && context_dir(&self.path()).is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--ignore requires a machine name or an --alias inside a directory, \
+                 only toolchains in a directory of their own have context files",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn run(&self) -> io::Result<()> {
+        self.validate()?;
+        let aliases = Path::new(".mc2aliases.yaml");
+        let path = self.path();
+        let ignore = path.with_file_name(".mc2ignore");
+
+        // Nothing is written if any of the files would fail
+        if let Some(machine) = self.machine.as_deref().filter(|_| self.alias.is_some()) {
+            check_alias(aliases, machine)?;
+        }
+        check_new(&path, self.force)?;
+        if self.ignore {
+            check_new(&ignore, self.force)?;
+        }
+
+        write_new(&path, &self.render(), self.force)?;
+        println!("Created {}", path.display());
+
+        if let (Some(alias), Some(machine)) = (&self.alias, &self.machine) {
+            add_alias(aliases, machine, alias)?;
+            println!("Added alias {} -> {}", machine, alias.display());
+        }
+
+        if self.ignore {
+            write_new(&ignore, IGNORE_FILE, self.force)?;
+            println!("Created {}", ignore.display());
+        }
+        Ok(())
+    }
+}
+
+/// Fails if `path` exists and `force` is not set
+fn check_new(path: &Path, force: bool) -> io::Result<()> {
+    if path.exists() && !force {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "{} already exists, use --force to overwrite",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub fn write_new(path: &Path, content: &str, force: bool) -> io::Result<()> {
+    check_new(path, force)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+}
+
+/// Fails if the alias file already defines `machine`
+fn check_alias(file: &Path, machine: &str) -> io::Result<()> {
+    if file.exists() {
+        let aliases: Option<HashMap<String, PathBuf>> =
+            serde_yaml::from_reader(BufReader::new(File::open(file)?))
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if aliases.is_some_and(|x| x.contains_key(machine)) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("alias {} already exists in {}", machine, file.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Appends an alias, so existing entries and comments are kept
+fn add_alias(file: &Path, machine: &str, target: &Path) -> io::Result<()> {
+    check_alias(file, machine)?;
+    // Quotes names and paths YAML would read differently
+    let entry = serde_yaml::Mapping::from_iter([(
+        machine.into(),
+        target.to_string_lossy().to_string().into(),
+    )]);
+    let entry =
+        serde_yaml::to_string(&entry).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let glued = fs::read(file).is_ok_and(|x| x.last().is_some_and(|x| *x != b'\n'));
+    let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+    if glued {
+        writeln!(file)?;
+    }
+    file.write_all(entry.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::BuildOptions;
+    use crate::docker::Dockerfile;
+
+    fn init(machine: Option<&str>) -> Init {
+        Init {
+            machine: machine.map(String::from),
+            base: None,
+            install: Vec::new(),
+            template: None,
+            alias: None,
+            ignore: false,
+            force: false,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut init = init(None);
+        init.base = Some("fedora:40".into());
+        init.install = Vec::from(["gcc".into(), "make".into()]);
+        assert_eq!(
+            init.render(),
+            "---\nbase: fedora:40\ninstall:\n  - gcc\n  - make\n---\n#!/bin/sh\n\n"
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        let mut init = init(None);
+        init.template = Some(Template::Node);
+        init.install = Vec::from(["npm".into(), "git".into()]);
+        assert_eq!(
+            init.render(),
+            "---\nbase: debian:trixie-slim\ninstall:\n  - nodejs\n  - npm\n  - git\n---\n\
+             #!/bin/sh\n\n# npm install -g <package>\n"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut init = init(Some("rust"));
+        init.template = Some(Template::Rust);
+        assert!(init.validate().is_ok());
+        init.base = Some("fedora:40".into());
+        let error = init.validate().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        init.template = None;
+        assert!(init.validate().is_ok());
+        init.machine = None;
+        init.alias = Some("../toolchains/rust".into());
+        assert!(init.validate().is_err());
+    }
+
+    #[test]
+    fn test_ignore() {
+        let mut unnamed = init(None);
+        unnamed.ignore = true;
+        assert!(unnamed.validate().is_err());
+
+        let mut init = init(Some("ctx"));
+        init.ignore = true;
+        assert!(init.validate().is_ok());
+        assert_eq!(init.path(), PathBuf::from_iter([".mc", "ctx", "ctx.yaml"]));
+
+        let dir = std::env::temp_dir().join(format!("mc2-init-{}", std::process::id()));
+        let path = dir.join(init.path());
+        write_new(&path, &init.render(), true).unwrap();
+        let content = format!("{}*.log\n", IGNORE_FILE);
+        write_new(&path.with_file_name(".mc2ignore"), &content, true).unwrap();
+        write_new(&path.with_file_name("kept.txt"), "", true).unwrap();
+        write_new(&path.with_file_name("build.log"), "", true).unwrap();
+        let mixin = Mixin::load(&path).unwrap();
+        let dockerfile = Dockerfile::convert(&mixin, &BuildOptions::default()).unwrap();
+        let mut rendered = io::BufWriter::new(Vec::new());
+        dockerfile.write_to(&mut rendered).unwrap();
+        let rendered = String::from_utf8(rendered.into_inner().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(rendered.contains("kept.txt"));
+        assert!(!rendered.contains("build.log"));
+    }
+
+    #[test]
+    fn test_add_alias() {
+        let dir = std::env::temp_dir().join(format!("mc2-alias-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(".mc2aliases.yaml");
+        fs::write(&file, "# aliases\nrust: ../rust").unwrap();
+        add_alias(&file, "node", Path::new("../my tools: node #1")).unwrap();
+        assert!(add_alias(&file, "rust", Path::new("x")).is_err());
+        let aliases: HashMap<String, PathBuf> =
+            serde_yaml::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            aliases,
+            HashMap::from([
+                ("rust".into(), "../rust".into()),
+                ("node".into(), "../my tools: node #1".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(init(None).path(), PathBuf::from("mc.yaml"));
+        assert_eq!(init(Some("_")).path(), PathBuf::from("mc.yaml"));
+        assert_eq!(
+            init(Some("rust")).path(),
+            PathBuf::from_iter([".mc", "rust.yaml"])
+        );
+        let mut init = init(Some("rust"));
+        init.alias = Some("../toolchains/rust".into());
+        assert_eq!(init.path(), PathBuf::from("../toolchains/rust.yaml"));
+    }
+}
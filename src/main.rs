@@ -1,17 +1,41 @@
+mod check;
 mod config;
 mod convert;
 mod docker;
+mod explain;
+mod export;
+mod forward;
+mod import;
+mod init;
+mod signal;
 
+use crate::check::Checker;
 use crate::config::{Mixin, Publish, UserConfig, Volume};
-use crate::docker::Dockerfile;
-use clap::Parser;
+use crate::convert::BuildOptions;
+use crate::docker::{Dockerfile, SidecarImage, Terminal};
+use crate::explain::Explanation;
+use crate::export::Export;
+use crate::import::Toolchain;
+use crate::init::Init;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
 use std::io;
-use std::io::{BufWriter, stdout};
-use std::path::PathBuf;
+use std::io::{BufWriter, Write, stdout};
+use std::path::{Path, PathBuf};
+use std::process;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, trailing_var_arg = true)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    trailing_var_arg = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Prints out generated docker file
     #[arg(short, long, default_value = "false")]
     dry_run: bool,
@@ -20,6 +44,10 @@ struct Cli {
     #[arg(short = 'F', long, default_value = "false")]
     force: bool,
 
+    /// Merges upgrade, defaults and installs into fewer image layers
+    #[arg(long, default_value = "false")]
+    squash_layers: bool,
+
     /// Force selects toolchain file
     #[arg(short, long)]
     file: Option<PathBuf>,
@@ -33,85 +61,234 @@ struct Cli {
     publish: Vec<Publish>,
 
     /// Environment variable, will be forwarded to docker run.
+    /// A bare KEY passes the host value through, `*` may be used as wildcard
     #[arg(short, long)]
     env: Vec<String>,
 
-    /// Will disable stdio in docker run
+    /// Reads environment variables from a dotenv file
+    #[arg(long)]
+    env_file: Vec<PathBuf>,
+
+    /// Does not forward the host's ssh agent into the container
+    #[arg(long, default_value = "false")]
+    no_ssh_agent: bool,
+
+    /// Does not forward X11, Wayland, audio and GPU devices into the container
+    #[arg(long, default_value = "false")]
+    no_gui: bool,
+
+    /// Non-interactive mode, disables stdin, terminal and GUI forwarding in docker run
     #[arg(long, default_value = "false")]
     ci: bool,
 
+    /// Runs an init process as PID 1 inside the container that forwards signals and reaps zombies
+    #[arg(long, default_value = "false")]
+    init: bool,
+
+    /// Forces allocation of a terminal, by default one is allocated if stdin and stdout are terminals
+    #[arg(long, default_value = "false", overrides_with = "no_tty")]
+    tty: bool,
+
+    /// Never allocates a terminal
+    #[arg(long, default_value = "false", overrides_with = "tty")]
+    no_tty: bool,
+
     /// Name of environment,
     /// Config will be searched at:
     /// mc.yml,
     /// .mc/mc.yaml,
     /// <machine>.yaml,
     /// .mc/<machine>.yaml,
-    /// .mc/<machine>/<machine>.yaml
+    /// .mc/<machine>/<machine>.yaml.
+    /// Machines named like a subcommand have to follow `--`
     machine: Option<String>,
 
     /// Command that is executed after the container is up
     cmd: Vec<String>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Lints and validates a toolchain without building it
+    Check {
+        /// Force selects toolchain file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: check::Format,
+
+        /// Name of environment, see main help for lookup paths
+        machine: Option<String>,
+    },
+    /// Shows the include tree and where each setting came from
+    Explain {
+        /// Force selects toolchain file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Name of environment, see main help for lookup paths
+        machine: Option<String>,
+    },
+    /// Writes the environment in a format other tools understand
+    Export {
+        /// Format to export to
+        #[arg(value_enum)]
+        format: export::Format,
+
+        /// Force selects toolchain file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Directory to write devcontainer files to, .devcontainer by default.
+        /// Compose and k8s are written to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Refers to the image built with --squash-layers
+        #[arg(long, default_value = "false")]
+        squash_layers: bool,
+
+        /// Name of environment, see main help for lookup paths
+        machine: Option<String>,
+    },
+    /// Translates a Dockerfile or devcontainer.json into a toolchain
+    Import {
+        /// Dockerfile, or devcontainer.json if it ends with .json
+        path: PathBuf,
+
+        /// Writes the toolchain to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Overwrites an existing output file
+        #[arg(short = 'F', long, default_value = "false")]
+        force: bool,
+    },
+    /// Creates a new toolchain file
+    Init {
+        /// Base docker image
+        #[arg(short, long)]
+        base: Option<String>,
+
+        /// Packages to install, comma separated
+        #[arg(short, long, value_delimiter = ',')]
+        install: Vec<String>,
+
+        /// Starter template, templates are Debian based
+        #[arg(short, long, value_enum)]
+        template: Option<init::Template>,
+
+        /// Writes the toolchain to <ALIAS>.yaml and registers it in .mc2aliases.yaml
+        #[arg(long)]
+        alias: Option<PathBuf>,
+
+        /// Writes the toolchain to .mc/<machine>/ with a .mc2ignore for its context files
+        #[arg(long, default_value = "false")]
+        ignore: bool,
+
+        /// Overwrites existing files
+        #[arg(short = 'F', long, default_value = "false")]
+        force: bool,
+
+        /// Name of environment, written to .mc/<machine>.yaml or mc.yaml if omitted
+        machine: Option<String>,
+    },
+}
+
 impl Cli {
+    pub fn terminal(&self) -> Terminal {
+        let mut terminal = if self.ci {
+            Terminal::default()
+        } else {
+            Terminal::detect()
+        };
+        if self.tty {
+            terminal = Terminal {
+                interactive: true,
+                tty: true,
+            };
+        } else if self.no_tty {
+            terminal.tty = false;
+        }
+        terminal
+    }
+
     pub fn append_docker(&self, dockerfile: &mut Dockerfile) -> bool {
         dockerfile.add_publishes(self.publish.iter());
         dockerfile.add_volumes(self.volumes.iter());
-        for env in &self.env {
-            let split = env.split_once('=');
-            if let Some((k, v)) = split {
-                dockerfile.add_env(k, v);
-            } else {
-                eprintln!("Invalid environment variable: {:?}", env);
-                return false;
+        if self.no_ssh_agent {
+            dockerfile.set_ssh_agent(false);
+        }
+        if self.init {
+            dockerfile.set_init(true);
+        }
+        if self.no_gui || self.ci {
+            dockerfile.disable_gui();
+        }
+        let (env, passthrough): (Vec<_>, Vec<_>) =
+            self.env.iter().partition(|env| env.contains('='));
+        let env = HashMap::from_iter(env.into_iter().filter_map(|env| {
+            env.split_once('=')
+                .map(|(k, v)| (k.to_string(), Some(v.to_string())))
+        }));
+        match config::resolve_env(&self.env_file, passthrough, Some(&env)) {
+            Ok(env) => {
+                for entry in env {
+                    dockerfile.set_env(&entry.key, entry.value.as_deref());
+                }
+                true
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                false
             }
         }
-        true
     }
 }
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+/// Finds the toolchain file for a machine, prints searched paths if none exists
+fn find_toolchain(file: &Option<PathBuf>, machine: &Option<String>) -> Option<PathBuf> {
+    if let Some(path) = file {
+        return Some(path.clone());
+    }
 
-    let path = match &cli.file {
-        Some(path) => path.clone(),
-        None => {
-            // Load alias file path from alias file if it exists
-            let alias_file: Option<PathBuf> = match &cli.machine {
-                Some(machine) => config::get_alias_from_config(machine),
-                None => None,
-            };
+    // Load alias file path from alias file if it exists
+    let alias_file: Option<PathBuf> = match machine {
+        Some(machine) => config::get_alias_from_config(machine),
+        None => None,
+    };
 
-            // Search paths
-            let paths = match &cli.machine {
-                Some(machine) if machine != "_" => Vec::from_iter(
-                    alias_file
-                        .into_iter()
-                        .chain(Mixin::lookup_paths_named(machine)),
-                ),
-                _ => Mixin::lookup_path_unnamed(),
-            };
+    // Search paths
+    let paths = match machine {
+        Some(machine) if machine != "_" => Vec::from_iter(
+            alias_file
+                .into_iter()
+                .chain(Mixin::lookup_paths_named(machine)),
+        ),
+        _ => Mixin::lookup_path_unnamed(),
+    };
 
-            // Find the first config that exists
-            let Some(path) = paths.iter().find_map(|path| {
-                if path.exists() {
-                    Some(path.clone())
-                } else {
-                    None
-                }
-            }) else {
-                eprintln!("toolchain not found in:");
-                for path in paths.iter() {
-                    eprintln!("- {}", &path.display());
-                }
-                return Ok(());
-            };
-            path
+    // Find the first config that exists
+    let path = paths.iter().find(|path| path.exists()).cloned();
+    if path.is_none() {
+        eprintln!("toolchain not found in:");
+        for path in paths.iter() {
+            eprintln!("- {}", &path.display());
         }
-    };
+    }
+    path
+}
 
-    // Load config
-    let config = match Mixin::load(&path) {
+/// Converts a toolchain and applies the user configs if `user_configs` is set, prints why
+/// if it fails to load or convert
+fn load_dockerfile(
+    path: &Path,
+    squash_layers: bool,
+    user_configs: bool,
+) -> io::Result<Option<Dockerfile>> {
+    let config = match Mixin::load(path) {
         Ok(config) => config,
         Err(e) => {
             eprintln!(
@@ -119,18 +296,215 @@ fn main() -> io::Result<()> {
                 path.display(),
                 e
             );
+            return Ok(None);
+        }
+    };
+
+    let user_configs = match user_configs {
+        true => match UserConfig::load_all() {
+            Ok(user_configs) => user_configs,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Ok(None);
+            }
+        },
+        false => Vec::new(),
+    };
+    let options = BuildOptions {
+        docker_socket: user_configs
+            .iter()
+            .rev()
+            .find_map(|(_, config)| config.docker_socket),
+        cache_mounts: docker::buildkit_available(),
+        squash_layers,
+    };
+    let mut dockerfile = match Dockerfile::convert(&config, &options) {
+        Ok(dockerfile) => dockerfile,
+        Err(e) => {
+            eprintln!(
+                "Failed to convert toolchain file ({}):\r\n{}",
+                path.display(),
+                e
+            );
+            return Ok(None);
+        }
+    };
+    for (_, config) in user_configs {
+        config.append_docker(&mut dockerfile)?;
+    }
+    Ok(Some(dockerfile))
+}
+
+/// Builds the machines services run and replaces them with the tag of their image
+fn build_sidecars(dockerfile: &mut Dockerfile, squash_layers: bool) -> io::Result<()> {
+    for sidecar in dockerfile.sidecars_mut() {
+        let SidecarImage::Machine(machine) = &sidecar.image else {
+            continue;
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Failed to load machine of service {}", sidecar.name),
+            )
+        };
+        let path = find_toolchain(&None, &Some(machine.clone())).ok_or_else(invalid)?;
+        let image = load_dockerfile(&path, squash_layers, true)?.ok_or_else(invalid)?;
+        if !image.exists()? {
+            image.build(false)?;
+        }
+        sidecar.image = SidecarImage::Image(image.tag()?);
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    match &cli.command {
+        Some(Commands::Check {
+            file,
+            format,
+            machine,
+        }) => {
+            let Some(path) = find_toolchain(file, machine) else {
+                process::exit(1);
+            };
+            let problems = Checker::check(&path);
+            check::print(&problems, *format);
+            if !problems.is_empty() {
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Explain { file, machine }) => {
+            let Some(path) = find_toolchain(file, machine) else {
+                process::exit(1);
+            };
+            let config = match Mixin::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            let user_configs = match UserConfig::load_all() {
+                Ok(user_configs) => user_configs,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            match Explanation::new(&config, &user_configs) {
+                Ok(explanation) => print!("{}", explanation),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
             return Ok(());
         }
+        Some(Commands::Export {
+            format,
+            file,
+            output,
+            squash_layers,
+            machine,
+        }) => {
+            let Some(path) = find_toolchain(file, machine) else {
+                process::exit(1);
+            };
+            // Exports may be shared with others, settings of this host stay out
+            let Some(dockerfile) = load_dockerfile(&path, *squash_layers, false)? else {
+                process::exit(1);
+            };
+            let export = Export {
+                format: *format,
+                machine: machine.clone(),
+                output: output.clone(),
+            };
+            if let Err(e) = export.run(&dockerfile) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Import {
+            path,
+            output,
+            force,
+        }) => {
+            let toolchain = match Toolchain::import(path) {
+                Ok(toolchain) => toolchain,
+                Err(e) => {
+                    eprintln!("Failed to import {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            };
+            for warning in &toolchain.warnings {
+                eprintln!("{}", warning);
+            }
+            // Volumes are relative to the toolchain file, stdout is assumed to go to mc.yaml
+            let project = std::env::current_dir()?;
+            let dir = output.as_deref().and_then(Path::parent);
+            let content = toolchain.render(&project, &project.join(dir.unwrap_or(&project)))?;
+            match output {
+                Some(output) => {
+                    if let Err(e) = init::write_new(output, &content, *force) {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                    println!("Created {}", output.display());
+                }
+                None => print!("{}", content),
+            }
+            return Ok(());
+        }
+        Some(Commands::Init {
+            base,
+            install,
+            template,
+            alias,
+            ignore,
+            force,
+            machine,
+        }) => {
+            let init = Init {
+                machine: machine.clone(),
+                base: base.clone(),
+                install: install.clone(),
+                template: *template,
+                alias: alias.clone(),
+                ignore: *ignore,
+                force: *force,
+            };
+            if let Err(e) = init.run() {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let Some(path) = find_toolchain(&cli.file, &cli.machine) else {
+        return Ok(());
     };
 
-    let mut dockerfile = Dockerfile::try_from(&config).expect("Failed to convert toolchain file");
-    UserConfig::load()?.append_docker(&mut dockerfile);
+    let Some(mut dockerfile) = load_dockerfile(&path, cli.squash_layers, true)? else {
+        process::exit(1);
+    };
     if !cli.append_docker(&mut dockerfile) {
         return Ok(());
     }
 
     if cli.dry_run {
-        dockerfile.write_to(&mut BufWriter::new(stdout()))?;
+        let mut stdout = BufWriter::new(stdout());
+        if let Some(base) = dockerfile.base() {
+            writeln!(stdout, "# Base image {}", base.tag()?)?;
+            base.write_to(&mut stdout)?;
+            writeln!(stdout, "\n# Image {}", dockerfile.tag()?)?;
+        }
+        dockerfile.write_to(&mut stdout)?;
         return Ok(());
     } else {
         if dockerfile.exists()? && !cli.force {
@@ -139,10 +513,35 @@ fn main() -> io::Result<()> {
             if cli.force {
                 println!("Force rebuild of image...");
             }
-            dockerfile.build()?;
+            dockerfile.build(cli.force)?;
+        }
+        build_sidecars(&mut dockerfile, cli.squash_layers)?;
+        let code = match dockerfile.run(&cli.cmd, cli.terminal()) {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        if code != 0 {
+            process::exit(code);
         }
-        dockerfile.run(&cli.cmd, !cli.ci)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_machine_named_like_subcommand() {
+        let cli = Cli::try_parse_from(["mc2", "check"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Check { .. })));
+        let cli = Cli::try_parse_from(["mc2", "--", "check", "make", "-j4"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.machine.as_deref(), Some("check"));
+        assert_eq!(cli.cmd, ["make", "-j4"]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::{io, mem, ptr};
+
+/// Signals forwarded to the container
+pub const FORWARDED: [i32; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Last received signal, 0 if none is pending
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the pending signal was sent by a process (kill) rather than the kernel
+/// (terminal). Terminal signals reach the whole foreground process group, so docker
+/// has already received them.
+static PENDING_FROM_USER: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let from_user = !info.is_null() && unsafe { (*info).si_code } == libc::SI_USER;
+    PENDING_FROM_USER.store(from_user as i32, Ordering::SeqCst);
+    PENDING.store(signal, Ordering::SeqCst);
+}
+
+/// Catches [`FORWARDED`] signals so mc2 outlives them and can tear down the container
+pub fn install() -> io::Result<()> {
+    for signal in FORWARDED {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Takes the pending signal, the flag tells whether it was sent by a process
+pub fn take() -> Option<(i32, bool)> {
+    match PENDING.swap(0, Ordering::SeqCst) {
+        0 => None,
+        signal => Some((signal, PENDING_FROM_USER.load(Ordering::SeqCst) != 0)),
+    }
+}
+
+/// Sends `signal` to the process `pid`
+pub fn send(pid: u32, signal: i32) -> io::Result<()> {
+    match unsafe { libc::kill(pid as libc::pid_t, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}